@@ -1,8 +1,15 @@
 use anchor_lang::prelude::*;
-use anchor_spl::{associated_token::AssociatedToken, token::{CloseAccount, Mint, Token, TokenAccount, Transfer}};
+use anchor_lang::solana_program::{program::invoke_signed, program_pack::Pack};
+use anchor_spl::{associated_token::AssociatedToken, token::{Burn, CloseAccount, Mint, MintTo, SetAuthority, Token, TokenAccount, Transfer}};
+use spl_token::instruction::AuthorityType;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+// The native program that verifies ed25519 signatures. We look for its instructions to redeem claim links.
+mod ed25519_program {
+    anchor_lang::declare_id!("Ed25519SigVerify111111111111111111111111111");
+}
+
 #[error]
 pub enum ErrorCode {
     #[msg("Wallet to withdraw from is not owned by owner")]
@@ -12,7 +19,138 @@ pub enum ErrorCode {
     #[msg("Delegate is not set correctly")]
     DelegateNotSetCorrectly,
     #[msg("Stage is invalid")]
-    StageInvalid
+    StageInvalid,
+    #[msg("Milestones are invalid")]
+    InvalidMilestones,
+    #[msg("Milestone was already released")]
+    MilestoneAlreadyReleased,
+    #[msg("Grant is released through milestones")]
+    GrantHasMilestones,
+    #[msg("Vesting schedule is invalid")]
+    InvalidVestingSchedule,
+    #[msg("Grant is released through its vesting schedule")]
+    GrantIsVesting,
+    #[msg("Grant is not a vesting grant")]
+    GrantIsNotVesting,
+    #[msg("No vested tokens to withdraw")]
+    NothingVested,
+    #[msg("Expiry must be in the future")]
+    InvalidExpiry,
+    #[msg("Grant has expired")]
+    GrantExpired,
+    #[msg("Grant has not expired yet")]
+    GrantNotExpired,
+    #[msg("Guaranteed claim window is invalid")]
+    InvalidClaimWindow,
+    #[msg("Grant cannot be pulled back during its guaranteed claim window")]
+    ClaimWindowActive,
+    #[msg("Grant has no arbiter")]
+    NoArbiter,
+    #[msg("Signer is not the arbiter of this grant")]
+    NotArbiter,
+    #[msg("Only Alice or the receipt holder can raise a dispute")]
+    NotAParty,
+    #[msg("Grant is frozen by a dispute")]
+    GrantDisputed,
+    #[msg("Resolution amount exceeds the escrow balance")]
+    InvalidResolution,
+    #[msg("Release has not been approved")]
+    ReleaseNotApproved,
+    #[msg("Signer is not the approver of this grant")]
+    NotApprover,
+    #[msg("Approver set is invalid")]
+    InvalidApproverSet,
+    #[msg("Approver already approved this release")]
+    AlreadyApproved,
+    #[msg("Preimage does not match the hashlock")]
+    InvalidPreimage,
+    #[msg("Claim link must be redeemed with a signature from its key")]
+    GrantIsClaimLink,
+    #[msg("Claim link signature is missing or invalid")]
+    InvalidClaimSignature,
+    #[msg("Swap grants are completed through complete_swap")]
+    GrantIsSwap,
+    #[msg("Grant is not a swap")]
+    GrantIsNotSwap,
+    #[msg("Pull back amount is invalid")]
+    InvalidPullBackAmount,
+    #[msg("Current receiver must co-sign the reassignment")]
+    ReceiverSignatureRequired,
+    #[msg("Claim was transferred to another beneficiary")]
+    ClaimTransferred,
+    #[msg("Fee is above the maximum")]
+    InvalidFee,
+    #[msg("Integrator fee is above the maximum")]
+    IntegratorFeeTooHigh,
+    #[msg("Integrator wallet is invalid")]
+    InvalidIntegratorWallet,
+    #[msg("SafePay is paused")]
+    ProgramPaused,
+    #[msg("Mint is not on the allowlist")]
+    MintNotAllowed,
+    #[msg("Amount must be greater than zero")]
+    ZeroAmount,
+    #[msg("Amount is below the minimum for this mint")]
+    AmountBelowMinimum,
+    #[msg("Amount is above the maximum for this mint")]
+    AmountAboveMaximum,
+    #[msg("Minimum amount is above the maximum")]
+    InvalidAmountLimits,
+    #[msg("Address is on the denylist")]
+    AddressDenied,
+    #[msg("Denylist entry does not match the address")]
+    InvalidDenylistEntry,
+    #[msg("Beneficiary wallet is invalid")]
+    InvalidBeneficiaryWallet,
+    #[msg("Grants approved by an approver set cannot be reassigned")]
+    GrantHasApproverSet,
+    #[msg("Receipt wallet does not hold the receipt")]
+    InvalidReceiptWallet,
+    #[msg("Receipt holder must co-sign to burn the receipt")]
+    ReceiptHolderSignatureRequired,
+    #[msg("Signer is not the upgrade authority of the program")]
+    NotUpgradeAuthority,
+}
+
+/// Sends a `TransferChecked` instruction to the Token program. Unlike a plain `Transfer`, the Token program
+/// verifies the mint and its decimals, so a mismatched mint can never be moved by mistake.
+///
+/// # Arguments
+///
+/// * `token_program` - the token program address
+/// * `from` - the Token account to debit
+/// * `mint` - the mint of the token being moved
+/// * `to` - the Token account to credit
+/// * `authority` - the owner (or PDA authority) of `from`
+/// * `signer_seeds` - the seeds to sign with when `authority` is a PDA
+/// * `amount` - the amount of tokens to move
+/// * `decimals` - the decimals of `mint`
+///
+fn transfer_checked<'info>(
+    token_program: AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    decimals: u8
+) -> ProgramResult {
+    let ix = spl_token::instruction::transfer_checked(
+        token_program.key,
+        from.key,
+        mint.key,
+        to.key,
+        authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+    invoke_signed(
+        &ix,
+        &[from, mint, to, authority, token_program],
+        signer_seeds,
+    )
 }
 
 // 
@@ -60,17 +198,17 @@ fn transfer_escrow_out<'info>(
     let outer = vec![inner.as_slice()];
 
     // Perform the actual transfer
-    let transfer_instruction = Transfer{
-        from: escrow_wallet.to_account_info(),
-        to: destination_wallet,
-        authority: state.to_account_info(),
-    };
-    let cpi_ctx = CpiContext::new_with_signer(
+    let decimals = spl_token::state::Mint::unpack(&mint_of_token_being_sent.try_borrow_data()?)?.decimals;
+    transfer_checked(
         token_program.to_account_info(),
-        transfer_instruction,
+        escrow_wallet.to_account_info(),
+        mint_of_token_being_sent.to_account_info(),
+        destination_wallet,
+        state.to_account_info(),
         outer.as_slice(),
-    );
-    anchor_spl::token::transfer(cpi_ctx, amount)?;
+        amount,
+        decimals,
+    )?;
 
 
     // Use the `reload()` function on an account to reload it's state. Since we performed the
@@ -98,18 +236,568 @@ fn transfer_escrow_out<'info>(
     Ok(())
 }
 
+/// Same as `transfer_escrow_out`, but `fee` out of `amount` goes to the protocol's `fee_vault` first.
+///
+/// # Arguments
+///
+/// * `fee_vault` - the fee recipient's Token account
+/// * `fee` - the protocol's share of `amount`, at most `amount`
+///
+fn transfer_escrow_out_with_fee<'info>(
+    user_sending: AccountInfo<'info>,
+    user_receiving: AccountInfo<'info>,
+    mint_of_token_being_sent: AccountInfo<'info>,
+    escrow_wallet: &mut Account<'info, TokenAccount>,
+    application_idx: u64,
+    state: AccountInfo<'info>,
+    state_bump: u8,
+    token_program: AccountInfo<'info>,
+    fee_vault: AccountInfo<'info>,
+    destination_wallet: AccountInfo<'info>,
+    amount: u64,
+    fee: u64,
+) -> ProgramResult {
+    if fee > 0 {
+        transfer_escrow_out(
+            user_sending.clone(),
+            user_receiving.clone(),
+            mint_of_token_being_sent.clone(),
+            escrow_wallet,
+            application_idx,
+            state.clone(),
+            state_bump,
+            token_program.clone(),
+            fee_vault,
+            fee,
+        )?;
+    }
+    if amount > fee {
+        transfer_escrow_out(
+            user_sending,
+            user_receiving,
+            mint_of_token_being_sent,
+            escrow_wallet,
+            application_idx,
+            state,
+            state_bump,
+            token_program,
+            destination_wallet,
+            amount - fee,
+        )?;
+    }
+    Ok(())
+}
+
+/// Moves lamports out of a SOL grant. The lamports sit directly on the `SolState` PDA, which is owned by
+/// this program, so we can debit it without a CPI to the System program.
+///
+/// # Arguments
+///
+/// * `state` - the SOL application state (PDA) holding the escrowed lamports
+/// * `destination` - the account that receives the lamports
+/// * `amount` - the amount of lamports to move
+///
+fn transfer_lamports_out<'info>(
+    state: AccountInfo<'info>,
+    destination: AccountInfo<'info>,
+    amount: u64
+) -> ProgramResult {
+    **state.try_borrow_mut_lamports()? -= amount;
+    **destination.try_borrow_mut_lamports()? += amount;
+    Ok(())
+}
+
+/// Checks that the instruction right before the current one is an ed25519 signature verification of
+/// `message` by `signer`. The runtime fails the whole transaction if that signature is wrong, so by the
+/// time we run we only need to make sure the verified key and message are the ones we expect.
+///
+/// # Arguments
+///
+/// * `instructions` - the instructions sysvar
+/// * `signer` - the public key that must have signed `message`
+/// * `message` - the exact message that must have been signed
+///
+fn verify_ed25519_signature(instructions: &AccountInfo, signer: &Pubkey, message: &[u8]) -> ProgramResult {
+    use anchor_lang::solana_program::sysvar::instructions::{load_current_index_checked, load_instruction_at_checked};
+
+    let current_index = load_current_index_checked(instructions)?;
+    if current_index == 0 {
+        return Err(ErrorCode::InvalidClaimSignature.into());
+    }
+    let ix = load_instruction_at_checked((current_index - 1) as usize, instructions)?;
+    if ix.program_id != ed25519_program::ID || !ix.accounts.is_empty() {
+        return Err(ErrorCode::InvalidClaimSignature.into());
+    }
+
+    // Layout: [num_signatures: u8, padding: u8] followed by one 14 byte offsets struct per signature:
+    // signature offset / ix index, public key offset / ix index, message offset / size / ix index.
+    let data = &ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        return Err(ErrorCode::InvalidClaimSignature.into());
+    }
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let signature_ix_index = read_u16(4);
+    let public_key_offset = read_u16(6) as usize;
+    let public_key_ix_index = read_u16(8);
+    let message_offset = read_u16(10) as usize;
+    let message_size = read_u16(12) as usize;
+    let message_ix_index = read_u16(14);
+
+    // Everything must live inside the ed25519 instruction itself (index u16::MAX), otherwise the
+    // verified data could be pointed at some other instruction.
+    if signature_ix_index != u16::MAX || public_key_ix_index != u16::MAX || message_ix_index != u16::MAX {
+        return Err(ErrorCode::InvalidClaimSignature.into());
+    }
+    let verified_key = data.get(public_key_offset..public_key_offset + 32);
+    let verified_message = data.get(message_offset..message_offset + message_size);
+    if verified_key != Some(signer.as_ref()) || verified_message != Some(message) {
+        return Err(ErrorCode::InvalidClaimSignature.into());
+    }
+    Ok(())
+}
+
+/// Checks that `address` is not on the denylist. Denylist entries are PDAs seeded by the address they
+/// block, so the caller passes the PDA of `address` and we make sure nothing lives there.
+///
+/// # Arguments
+///
+/// * `denylist_entry` - the denylist PDA of `address`, which must be empty
+/// * `address` - the address to check
+///
+fn check_not_denied(denylist_entry: &AccountInfo, address: &Pubkey) -> ProgramResult {
+    if is_denied(denylist_entry, address)? {
+        msg!("Address {} is denied", address);
+        return Err(ErrorCode::AddressDenied.into());
+    }
+    Ok(())
+}
+
+/// Same as `check_not_denied`, for the paths that route funds around a denied address instead of failing.
+fn is_denied(denylist_entry: &AccountInfo, address: &Pubkey) -> Result<bool, ProgramError> {
+    let (expected_entry, _) = Pubkey::find_program_address(&[b"denied".as_ref(), address.as_ref()], &ID);
+    if denylist_entry.key() != expected_entry {
+        return Err(ErrorCode::InvalidDenylistEntry.into());
+    }
+    Ok(denylist_entry.owner == &ID && !denylist_entry.data_is_empty())
+}
+
+/// Fails while the admin has paused SafePay. Every instruction that opens a grant or pays out checks it,
+/// only the paths that hand the funds back to Alice (pull backs, rejections, expiries and dispute refunds)
+/// stay open.
+fn check_not_paused(config: &Config) -> ProgramResult {
+    if config.paused {
+        return Err(ErrorCode::ProgramPaused.into());
+    }
+    Ok(())
+}
+
+/// Checks that `signer` is the upgrade authority of SafePay, as recorded in the program's `ProgramData`
+/// account by the upgradeable BPF loader. A program deployed as immutable has no authority at all.
+///
+/// # Arguments
+///
+/// * `program_data` - the `ProgramData` account of SafePay
+/// * `signer` - the key that claims to be the upgrade authority
+fn check_upgrade_authority(program_data: &AccountInfo, signer: &Pubkey) -> ProgramResult {
+    use anchor_lang::solana_program::bpf_loader_upgradeable;
+
+    let (expected_program_data, _) = Pubkey::find_program_address(&[ID.as_ref()], &bpf_loader_upgradeable::ID);
+    if program_data.key() != expected_program_data || program_data.owner != &bpf_loader_upgradeable::ID {
+        return Err(ErrorCode::NotUpgradeAuthority.into());
+    }
+
+    // Layout: a u32 enum tag (3 for `ProgramData`), the u64 slot of the last deployment, then the
+    // authority as an `Option<Pubkey>`: a u8 flag followed by the key.
+    let data = program_data.try_borrow_data()?;
+    if data.len() < 45 || data[0..4] != 3u32.to_le_bytes() || data[12] != 1 || &data[13..45] != signer.as_ref() {
+        return Err(ErrorCode::NotUpgradeAuthority.into());
+    }
+    Ok(())
+}
+
+/// Populates a freshly created `State` and moves `amount` tokens from Alice's wallet into the escrow.
+/// Shared by every instruction that opens a grant through `InitializeNewGrant`.
+fn deposit_into_escrow<'info>(accounts: &mut InitializeNewGrant<'info>, application_idx: u64, state_bump: u8, amount: u64) -> ProgramResult {
+
+    // No new grants while the protocol is paused.
+    check_not_paused(&accounts.config)?;
+
+    // Only mints approved by the admin can be escrowed. A mint that was never allowed (or was disallowed)
+    // has no entry at its PDA, so we check the address and ownership by hand to fail with a clear error.
+    let (allowed_mint_key, _) = Pubkey::find_program_address(
+        &[b"allowed_mint".as_ref(), accounts.mint_of_token_being_sent.key().as_ref()],
+        &ID,
+    );
+    let allowed_mint = &accounts.allowed_mint;
+    if allowed_mint.key() != allowed_mint_key || allowed_mint.owner != &ID || allowed_mint.data_is_empty() {
+        msg!("Mint {} is not allowed", accounts.mint_of_token_being_sent.key());
+        return Err(ErrorCode::MintNotAllowed.into());
+    }
+    Account::<AllowedMint>::try_from(allowed_mint)?.check_amount(amount)?;
+
+    check_not_denied(&accounts.sender_denylist_entry, accounts.user_sending.key)?;
+    check_not_denied(&accounts.receiver_denylist_entry, accounts.user_receiving.key)?;
+
+    // Set the state attributes
+    let state = &mut accounts.application_state;
+    state.idx = application_idx;
+    state.user_sending = accounts.user_sending.key().clone();
+    state.user_receiving = accounts.user_receiving.key().clone();
+    state.mint_of_token_being_sent = accounts.mint_of_token_being_sent.key().clone();
+    state.escrow_wallet = accounts.escrow_wallet_state.key().clone();
+    state.receipt_mint = accounts.receipt_mint.key().clone();
+    state.amount_tokens = amount;
+
+    msg!("Initialized new Safe Transfer instance for {}", amount);
+
+    // CPI time! we now need to call into the Token program to transfer our funds to the 
+    // Escrow wallet. Our state account account is a PDA, which means that no private key
+    // exists for the corresponding public key and therefore this key was not signed in the original 
+    // transaction. Our program is the only entity that can programmatically sign for the PDA
+    // and we can do this by specifying the PDA "derivation hash key" and using `invoke_signed()`.
+
+    // This specific step is very different compared to Ethereum. In Ethereum, accounts need to first set allowances towards 
+    // a specific contract (like ZeroEx, Uniswap, Curve..) before the contract is able to withdraw funds. In this other case,
+    // the SafePay program can use Bob's signature to "authenticate" the `transfer()` instruction sent to the token contract.
+    let bump_vector = state_bump.to_le_bytes();
+    let mint_of_token_being_sent_pk = accounts.mint_of_token_being_sent.key().clone();
+    let application_idx_bytes = application_idx.to_le_bytes();
+    let inner = vec![
+        b"state".as_ref(),
+        accounts.user_sending.key.as_ref(),
+        accounts.user_receiving.key.as_ref(),
+        mint_of_token_being_sent_pk.as_ref(), 
+        application_idx_bytes.as_ref(),
+        bump_vector.as_ref(),
+    ];
+    let outer = vec![inner.as_slice()];
+
+    // The `?` at the end will cause the function to return early in case of an error.
+    // This pattern is common in Rust.
+    transfer_checked(
+        accounts.token_program.to_account_info(),
+        accounts.wallet_to_withdraw_from.to_account_info(),
+        accounts.mint_of_token_being_sent.to_account_info(),
+        accounts.escrow_wallet_state.to_account_info(),
+        accounts.user_sending.to_account_info(),
+        outer.as_slice(),
+        state.amount_tokens,
+        accounts.mint_of_token_being_sent.decimals,
+    )?;
+
+    // A claim link is redeemed with a signature from its key, which never signs a transaction and so
+    // could never burn a receipt. Its receipt mint is locked without minting anything instead.
+    let receipt_amount = if state.is_claim_link { 0 } else { 1 };
+    mint_receipt(
+        accounts.token_program.to_account_info(),
+        accounts.receipt_mint.to_account_info(),
+        accounts.receipt_wallet.to_account_info(),
+        state.to_account_info(),
+        outer.as_slice(),
+        receipt_amount,
+    )?;
+
+    // Mark stage as deposited.
+    state.stage = Stage::FundsDeposited.to_code();
+    Ok(())
+}
+
+/// Mints the one-of-one receipt token of a grant and then removes the mint authority, so no second
+/// token can ever exist. Whoever holds the receipt holds the right to claim the grant: the program
+/// keeps no other record of who that is.
+///
+/// # Arguments
+///
+/// * `token_program` - the token program address
+/// * `receipt_mint` - the receipt mint (PDA), whose mint authority is the application state
+/// * `receipt_wallet` - the Token account that receives the receipt
+/// * `state` - the application state public key (PDA)
+/// * `signer_seeds` - the seeds of `state`
+/// * `amount` - 1, or 0 to only lock the mint of a grant that isn't claimed with a receipt
+///
+fn mint_receipt<'info>(
+    token_program: AccountInfo<'info>,
+    receipt_mint: AccountInfo<'info>,
+    receipt_wallet: AccountInfo<'info>,
+    state: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> ProgramResult {
+    if amount > 0 {
+        let mint_instruction = MintTo{
+            mint: receipt_mint.clone(),
+            to: receipt_wallet,
+            authority: state.clone(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(
+            token_program.clone(),
+            mint_instruction,
+            signer_seeds,
+        );
+        anchor_spl::token::mint_to(cpi_ctx, amount)?;
+    }
+
+    let set_authority_instruction = SetAuthority{
+        current_authority: state,
+        account_or_mint: receipt_mint,
+    };
+    let cpi_ctx = CpiContext::new_with_signer(
+        token_program,
+        set_authority_instruction,
+        signer_seeds,
+    );
+    anchor_spl::token::set_authority(cpi_ctx, AuthorityType::MintTokens, None)
+}
+
 #[program]
 pub mod safe_pay {
 
-    use anchor_spl::token::Transfer;
     use super::*;
 
-    pub fn complete_grant(ctx: Context<CompleteGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
-        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+    pub fn complete_grant(ctx: Context<CompleteGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, preimage: Option<Vec<u8>>) -> ProgramResult {
+        // Pausing freezes payouts, but Alice can still pull back her funds.
+        check_not_paused(&ctx.accounts.config)?;
+
+        // Same goes for grants involving a denied address.
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        check_not_denied(&ctx.accounts.beneficiary_denylist_entry, ctx.accounts.beneficiary.key)?;
+
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage == Stage::Disputed {
+            return Err(ErrorCode::GrantDisputed.into());
+        }
+
+        // Grants with an approver can only be claimed once the approver signed off on the release.
+        let requires_approval = ctx.accounts.application_state.approver.is_some();
+        if requires_approval && current_stage == Stage::FundsDeposited {
+            return Err(ErrorCode::ReleaseNotApproved.into());
+        }
+        let expected_stage = if requires_approval { Stage::Approved } else { Stage::FundsDeposited };
+        if current_stage != expected_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // Milestone grants are only paid out by Alice, one tranche at a time.
+        if !ctx.accounts.application_state.milestones.is_empty() {
+            return Err(ErrorCode::GrantHasMilestones.into());
+        }
+
+        // Claim links are redeemed through `claim_link`, which lets the holder pick the destination.
+        if ctx.accounts.application_state.is_claim_link {
+            return Err(ErrorCode::GrantIsClaimLink.into());
+        }
+
+        // Swaps only release once Bob pays his side through `complete_swap`.
+        if ctx.accounts.application_state.mint_to_receive.is_some() {
+            return Err(ErrorCode::GrantIsSwap.into());
+        }
+
+        // Vesting grants are streamed to Bob through `withdraw_vested`.
+        if ctx.accounts.application_state.is_vesting() {
+            return Err(ErrorCode::GrantIsVesting.into());
+        }
+
+        // Once expired, the funds can only go back to Alice.
+        if ctx.accounts.application_state.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ErrorCode::GrantExpired.into());
+        }
+
+        // HTLC grants can only be claimed by revealing the secret behind the hashlock.
+        if let Some(hashlock) = ctx.accounts.application_state.hashlock {
+            let preimage = preimage.ok_or(ErrorCode::InvalidPreimage)?;
+            if anchor_lang::solana_program::hash::hash(&preimage).to_bytes() != hashlock {
+                return Err(ErrorCode::InvalidPreimage.into());
+            }
+        }
+
+        // The integrator and the protocol take their cut first, Bob gets the rest.
+        let amount = ctx.accounts.application_state.amount_tokens;
+        let integrator_fee = ctx.accounts.application_state.integrator_fee();
+        if integrator_fee > 0 {
+            let integrator_wallet = Account::<TokenAccount>::try_from(&ctx.accounts.integrator_wallet)?;
+            let is_valid_wallet = Some(integrator_wallet.owner) == ctx.accounts.application_state.integrator
+                && integrator_wallet.mint == ctx.accounts.mint_of_token_being_sent.key();
+            if !is_valid_wallet {
+                return Err(ErrorCode::InvalidIntegratorWallet.into());
+            }
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.integrator_wallet.to_account_info(),
+                integrator_fee
+            )?;
+        }
+        // The protocol fee can be raised while the grant is open: cap it so the two cuts never exceed the grant.
+        let fee = ctx.accounts.config.fee_for(amount).min(amount - integrator_fee);
+        if fee > 0 {
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.fee_vault.to_account_info(),
+                fee
+            )?;
+        }
+        if amount > integrator_fee + fee {
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.wallet_to_deposit_to.to_account_info(),
+                amount - integrator_fee - fee
+            )?;
+        }
+
+        // The claim is spent: burn the receipt so it can't be traded anymore.
+        let burn_instruction = Burn{
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+        anchor_spl::token::burn(cpi_ctx, 1)?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+        Ok(())
+    }
+
+    pub fn pull_back(ctx: Context<PullBackInstruction>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage == Stage::Disputed {
+            return Err(ErrorCode::GrantDisputed.into());
+        }
+        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PartiallyReleased || current_stage == Stage::PullBackComplete;
+        if !is_valid_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // Bob is guaranteed he can claim until the window ends, so Alice can't front-run him.
+        if Clock::get()?.unix_timestamp < ctx.accounts.application_state.guaranteed_until_ts {
+            return Err(ErrorCode::ClaimWindowActive.into());
+        }
+
+        // In vesting mode, whatever vested so far belongs to the receipt holder: send it to them first
+        // and only refund the unvested portion to Alice. A receipt burned outside of SafePay leaves
+        // nobody to pay, and a denied address can't be paid, so then everything goes back to Alice.
+        // A pause doesn't hold the vested share back: it is already owed, and keeping it in escrow
+        // would block Alice's refund of the rest.
+        let mut wallet_amount = ctx.accounts.escrow_wallet_state.amount;
+        if ctx.accounts.application_state.is_vesting() && ctx.accounts.receipt_mint.supply > 0 {
+            let now = Clock::get()?.unix_timestamp;
+            let state = &ctx.accounts.application_state;
+            let vested_owed = state.vested_amount(now) - state.amount_withdrawn;
+            if vested_owed > 0 {
+                let receipt_wallet = Account::<TokenAccount>::try_from(&ctx.accounts.receipt_wallet)?;
+                if receipt_wallet.mint != state.receipt_mint || receipt_wallet.amount != 1 {
+                    return Err(ErrorCode::InvalidReceiptWallet.into());
+                }
+                let is_any_denied = is_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?
+                    || is_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?
+                    || is_denied(&ctx.accounts.beneficiary_denylist_entry, &receipt_wallet.owner)?;
+                if !is_any_denied {
+                    let wallet_to_deposit_to = Account::<TokenAccount>::try_from(&ctx.accounts.wallet_to_deposit_to)?;
+                    let is_valid_wallet = wallet_to_deposit_to.owner == receipt_wallet.owner
+                        && wallet_to_deposit_to.mint == ctx.accounts.mint_of_token_being_sent.key();
+                    if !is_valid_wallet {
+                        return Err(ErrorCode::InvalidBeneficiaryWallet.into());
+                    }
+                    transfer_escrow_out(
+                        ctx.accounts.user_sending.to_account_info(),
+                        ctx.accounts.user_receiving.to_account_info(),
+                        ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                        &mut ctx.accounts.escrow_wallet_state,
+                        application_idx,
+                        ctx.accounts.application_state.to_account_info(),
+                        state_bump,
+                        ctx.accounts.token_program.to_account_info(),
+                        ctx.accounts.wallet_to_deposit_to.to_account_info(),
+                        vested_owed,
+                    )?;
+                    ctx.accounts.application_state.amount_withdrawn += vested_owed;
+                    wallet_amount -= vested_owed;
+                }
+            }
+        }
+
+        if wallet_amount > 0 {
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.refund_wallet.to_account_info(),
+                wallet_amount,
+            )?;
+        }
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::PullBackComplete.to_code();
+
+        Ok(())
+    }
+
+    pub fn pull_back_partial(ctx: Context<PullBackInstruction>, application_idx: u64, state_bump: u8, _wallet_bump: u8, amount: u64) -> ProgramResult {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage == Stage::Disputed {
+            return Err(ErrorCode::GrantDisputed.into());
+        }
+        if current_stage != Stage::FundsDeposited {
             msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
             return Err(ErrorCode::StageInvalid.into());
         }
 
+        // Bob is guaranteed he can claim until the window ends, so Alice can't front-run him.
+        if Clock::get()?.unix_timestamp < ctx.accounts.application_state.guaranteed_until_ts {
+            return Err(ErrorCode::ClaimWindowActive.into());
+        }
+
+        // Milestone and vesting grants derive what Bob is owed from `amount_tokens`, so they can
+        // only be pulled back as a whole.
+        if !ctx.accounts.application_state.milestones.is_empty() {
+            return Err(ErrorCode::GrantHasMilestones.into());
+        }
+        if ctx.accounts.application_state.is_vesting() {
+            return Err(ErrorCode::GrantIsVesting.into());
+        }
+
+        // Bob settles a swap all at once, Alice can't shrink it under him.
+        if ctx.accounts.application_state.mint_to_receive.is_some() {
+            return Err(ErrorCode::GrantIsSwap.into());
+        }
+
+        let grant_amount = ctx.accounts.application_state.amount_tokens;
+        if amount == 0 || amount > grant_amount {
+            msg!("Cannot pull back {} out of {}", amount, grant_amount);
+            return Err(ErrorCode::InvalidPullBackAmount.into());
+        }
+
+        // Pulling back the whole grant also sweeps whatever was sent straight to the escrow, so that
+        // `transfer_escrow_out` empties and closes it.
+        let is_full_pull_back = amount == grant_amount;
+        let transfer_amount = if is_full_pull_back { ctx.accounts.escrow_wallet_state.amount } else { amount };
         transfer_escrow_out(
             ctx.accounts.user_sending.to_account_info(),
             ctx.accounts.user_receiving.to_account_info(),
@@ -119,23 +807,130 @@ pub mod safe_pay {
             ctx.accounts.application_state.to_account_info(),
             state_bump,
             ctx.accounts.token_program.to_account_info(),
-            ctx.accounts.wallet_to_deposit_to.to_account_info(),
-            ctx.accounts.application_state.amount_tokens
+            ctx.accounts.refund_wallet.to_account_info(),
+            transfer_amount,
         )?;
 
+        // Whatever is left stays claimable by Bob.
         let state = &mut ctx.accounts.application_state;
-        state.stage = Stage::EscrowComplete.to_code();
+        state.amount_tokens -= amount;
+        if is_full_pull_back {
+            state.stage = Stage::PullBackComplete.to_code();
+        }
         Ok(())
     }
 
-    pub fn pull_back(ctx: Context<PullBackInstruction>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+    pub fn initialize_new_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, amount: u64, expiry_ts: Option<i64>, guaranteed_until_ts: Option<i64>, arbiter: Option<Pubkey>, approver: Option<Pubkey>, integrator: Option<Pubkey>, integrator_fee_bps: u16) -> ProgramResult {
+        if let Some(expiry_ts) = expiry_ts {
+            if expiry_ts <= Clock::get()?.unix_timestamp {
+                msg!("Expiry {} is in the past", expiry_ts);
+                return Err(ErrorCode::InvalidExpiry.into());
+            }
+            ctx.accounts.application_state.expiry_ts = expiry_ts;
+        }
+
+        // Bob can't be promised a claim window that outlives the grant itself.
+        if let Some(guaranteed_until_ts) = guaranteed_until_ts {
+            if expiry_ts.map_or(false, |expiry_ts| guaranteed_until_ts > expiry_ts) {
+                msg!("Claim window ends at {}, after the grant expires", guaranteed_until_ts);
+                return Err(ErrorCode::InvalidClaimWindow.into());
+            }
+            ctx.accounts.application_state.guaranteed_until_ts = guaranteed_until_ts;
+        }
+
+        // The platform that embedded SafePay can take a cut of the payment, up to the protocol's limit.
+        if integrator.is_none() && integrator_fee_bps > 0 {
+            return Err(ErrorCode::InvalidFee.into());
+        }
+        if integrator_fee_bps > ctx.accounts.config.max_integrator_fee_bps {
+            msg!("Integrator fee {} is above the maximum of {}", integrator_fee_bps, ctx.accounts.config.max_integrator_fee_bps);
+            return Err(ErrorCode::IntegratorFeeTooHigh.into());
+        }
+
+        ctx.accounts.application_state.arbiter = arbiter;
+        ctx.accounts.application_state.approver = approver;
+        ctx.accounts.application_state.integrator = integrator;
+        ctx.accounts.application_state.integrator_fee_bps = integrator_fee_bps;
+
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
+    }
+
+    pub fn approve_release(ctx: Context<ApproveRelease>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        let state = &mut ctx.accounts.application_state;
+        if Stage::from(state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if state.approver != Some(ctx.accounts.approver.key()) {
+            return Err(ErrorCode::NotApprover.into());
+        }
+
+        // Bob can now claim, and Alice can no longer pull back.
+        state.stage = Stage::Approved.to_code();
+        Ok(())
+    }
+
+    // A multisig release is a grant whose approver is its `ApproverSet` PDA: that key can never
+    // sign, so the grant only reaches `Stage::Approved` through `approve` once M of N approvers agreed.
+    pub fn initialize_approver_set(ctx: Context<InitializeApproverSet>, _application_idx: u64, _state_bump: u8, _approvals_bump: u8, approvers: Vec<Pubkey>, threshold: u8) -> ProgramResult {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if ctx.accounts.application_state.approver != Some(ctx.accounts.approver_set.key()) {
+            msg!("Grant must be initialized with its approver set as approver");
+            return Err(ErrorCode::InvalidApproverSet.into());
+        }
+
+        let has_duplicates = approvers.iter().enumerate().any(|(i, a)| approvers[..i].contains(a));
+        if approvers.len() > MAX_APPROVERS || threshold == 0 || threshold as usize > approvers.len() || has_duplicates {
+            msg!("Invalid {}-of-{} approver set", threshold, approvers.len());
+            return Err(ErrorCode::InvalidApproverSet.into());
+        }
+
+        let approver_set = &mut ctx.accounts.approver_set;
+        approver_set.approvers = approvers;
+        approver_set.threshold = threshold;
+        approver_set.approvals = 0;
+        Ok(())
+    }
+
+    pub fn approve(ctx: Context<Approve>, _application_idx: u64, _state_bump: u8, _approvals_bump: u8) -> ProgramResult {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        let approver = ctx.accounts.approver.key();
+        let approver_set = &mut ctx.accounts.approver_set;
+        let position = approver_set.approvers
+            .iter()
+            .position(|a| *a == approver)
+            .ok_or(ErrorCode::NotApprover)?;
+        let bit = 1u16 << position;
+        if approver_set.approvals & bit != 0 {
+            return Err(ErrorCode::AlreadyApproved.into());
+        }
+        approver_set.approvals |= bit;
+
+        let approval_count = approver_set.approvals.count_ones();
+        msg!("{} of {} approvals", approval_count, approver_set.threshold);
+        if approval_count >= approver_set.threshold as u32 {
+            let state = &mut ctx.accounts.application_state;
+            state.stage = Stage::Approved.to_code();
+        }
+        Ok(())
+    }
+
+    pub fn reject_grant(ctx: Context<RejectGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
         let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
-        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PullBackComplete;
-        if !is_valid_stage {
+        if current_stage != Stage::FundsDeposited && current_stage != Stage::Approved {
             msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
             return Err(ErrorCode::StageInvalid.into());
         }
 
+        // Everything goes back to Alice. Both the escrow and the state account are closed, returning
+        // their rent to her as well.
         let wallet_amount = ctx.accounts.escrow_wallet_state.amount;
         transfer_escrow_out(
             ctx.accounts.user_sending.to_account_info(),
@@ -149,192 +944,1936 @@ pub mod safe_pay {
             ctx.accounts.refund_wallet.to_account_info(),
             wallet_amount,
         )?;
-        let state = &mut ctx.accounts.application_state;
-        state.stage = Stage::PullBackComplete.to_code();
 
+        // The claim is given up: burn the receipt so it can't be traded anymore.
+        let burn_instruction = Burn{
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+        anchor_spl::token::burn(cpi_ctx, 1)?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::Rejected.to_code();
         Ok(())
     }
 
-    pub fn initialize_new_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, amount: u64) -> ProgramResult {
+    pub fn reassign_receiver(ctx: Context<ReassignReceiver>, application_idx: u64, state_bump: u8, _wallet_bump: u8, new_state_bump: u8, _new_wallet_bump: u8, _new_receipt_bump: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.new_receiver_denylist_entry, ctx.accounts.new_user_receiving.key)?;
 
-        // Set the state attributes
-        let state = &mut ctx.accounts.application_state;
-        state.idx = application_idx;
-        state.user_sending = ctx.accounts.user_sending.key().clone();
-        state.user_receiving = ctx.accounts.user_receiving.key().clone();
-        state.mint_of_token_being_sent = ctx.accounts.mint_of_token_being_sent.key().clone();
-        state.escrow_wallet = ctx.accounts.escrow_wallet_state.key().clone();
-        state.amount_tokens = amount;
-
-        msg!("Initialized new Safe Transfer instance for {}", amount);
-
-        // CPI time! we now need to call into the Token program to transfer our funds to the 
-        // Escrow wallet. Our state account account is a PDA, which means that no private key
-        // exists for the corresponding public key and therefore this key was not signed in the original 
-        // transaction. Our program is the only entity that can programmatically sign for the PDA
-        // and we can do this by specifying the PDA "derivation hash key" and using `CpiContext::new_with_signer()`.
-
-        // This specific step is very different compared to Ethereum. In Ethereum, accounts need to first set allowances towards 
-        // a specific contract (like ZeroEx, Uniswap, Curve..) before the contract is able to withdraw funds. In this other case,
-        // the SafePay program can use Bob's signature to "authenticate" the `transfer()` instruction sent to the token contract.
-        let bump_vector = state_bump.to_le_bytes();
-        let mint_of_token_being_sent_pk = ctx.accounts.mint_of_token_being_sent.key().clone();
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PartiallyReleased || current_stage == Stage::Approved;
+        if !is_valid_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // Alice alone can redirect a grant, unless Bob was already promised it: an approved release or an
+        // active claim window can only be moved with his consent.
+        let now = Clock::get()?.unix_timestamp;
+        let receiver_has_claim = current_stage == Stage::Approved || now < ctx.accounts.application_state.guaranteed_until_ts;
+        if receiver_has_claim && !ctx.accounts.user_receiving.is_signer {
+            return Err(ErrorCode::ReceiverSignatureRequired.into());
+        }
+
+        // Bob already sold his receipt, the claim is no longer his (or Alice's) to redirect. Claim links
+        // have no receipt, the link key is all there is.
+        let receipt_wallet = &ctx.accounts.receipt_wallet;
+        let receiver_holds_receipt = receipt_wallet.owner == ctx.accounts.user_receiving.key() && receipt_wallet.amount == 1;
+        if !ctx.accounts.application_state.is_claim_link && !receiver_holds_receipt {
+            return Err(ErrorCode::ClaimTransferred.into());
+        }
+
+        // The approver set is seeded by the receiver as well, so it can't follow the grant: the new state
+        // would point at the old set, which can never mark the new grant as approved.
+        let (approver_set_key, _) = Pubkey::find_program_address(
+            &[
+                b"approvals".as_ref(),
+                ctx.accounts.user_sending.key.as_ref(),
+                ctx.accounts.user_receiving.key.as_ref(),
+                ctx.accounts.mint_of_token_being_sent.key().as_ref(),
+                application_idx.to_le_bytes().as_ref(),
+            ],
+            &ID,
+        );
+        if ctx.accounts.application_state.approver == Some(approver_set_key) {
+            return Err(ErrorCode::GrantHasApproverSet.into());
+        }
+
+        // `user_receiving` is part of the seeds, so the grant can't be edited in place. Copy it over to
+        // the PDA pair of the new receiver, keeping every other term of the grant as is.
+        let mut migrated = (*ctx.accounts.application_state).clone();
+        migrated.user_receiving = ctx.accounts.new_user_receiving.key();
+        migrated.escrow_wallet = ctx.accounts.new_escrow_wallet_state.key();
+        migrated.receipt_mint = ctx.accounts.new_receipt_mint.key();
+        *ctx.accounts.new_application_state = migrated;
+
+        // The new receiver gets a receipt of its own. The old one is worthless once the old state is closed.
+        let receipt_amount = if ctx.accounts.application_state.is_claim_link { 0 } else { 1 };
+        let bump_vector = new_state_bump.to_le_bytes();
+        let mint_of_token_being_sent_pk = ctx.accounts.mint_of_token_being_sent.key();
         let application_idx_bytes = application_idx.to_le_bytes();
         let inner = vec![
             b"state".as_ref(),
             ctx.accounts.user_sending.key.as_ref(),
-            ctx.accounts.user_receiving.key.as_ref(),
-            mint_of_token_being_sent_pk.as_ref(), 
+            ctx.accounts.new_user_receiving.key.as_ref(),
+            mint_of_token_being_sent_pk.as_ref(),
             application_idx_bytes.as_ref(),
             bump_vector.as_ref(),
         ];
         let outer = vec![inner.as_slice()];
+        mint_receipt(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.new_receipt_mint.to_account_info(),
+            ctx.accounts.new_receipt_wallet.to_account_info(),
+            ctx.accounts.new_application_state.to_account_info(),
+            outer.as_slice(),
+            receipt_amount,
+        )?;
+
+        // Moving the whole balance closes the old escrow, and the `close` constraint takes care of the old state.
+        let wallet_amount = ctx.accounts.escrow_wallet_state.amount;
+        transfer_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.new_escrow_wallet_state.to_account_info(),
+            wallet_amount,
+        )?;
+
+        msg!("Reassigned grant from {} to {}", ctx.accounts.user_receiving.key, ctx.accounts.new_user_receiving.key);
+        Ok(())
+    }
+
+    pub fn transfer_claim(ctx: Context<TransferClaim>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PartiallyReleased || current_stage == Stage::Approved;
+        if !is_valid_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
 
-        // Below is the actual instruction that we are going to send to the Token program.
+        // The receipt follows the claim.
         let transfer_instruction = Transfer{
-            from: ctx.accounts.wallet_to_withdraw_from.to_account_info(),
-            to: ctx.accounts.escrow_wallet_state.to_account_info(),
-            authority: ctx.accounts.user_sending.to_account_info(),
+            from: ctx.accounts.receipt_wallet.to_account_info(),
+            to: ctx.accounts.new_receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
         };
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            transfer_instruction,
-            outer.as_slice(),
-        );
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), transfer_instruction);
+        anchor_spl::token::transfer(cpi_ctx, 1)?;
+
+        // From now on every payout to the receiving side goes to the new holder instead.
+        msg!("Claim transferred from {} to {}", ctx.accounts.beneficiary.key, ctx.accounts.new_beneficiary.key);
+        Ok(())
+    }
+
+    pub fn close_grant(ctx: Context<CloseGrant>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        // Once the grant is settled the state account has no further use: the `close` constraint
+        // wipes it and hands its rent back to Alice.
+        if !Stage::from(ctx.accounts.application_state.stage)?.is_terminal() {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        Ok(())
+    }
+
+    pub fn raise_dispute(ctx: Context<RaiseDispute>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        let state = &mut ctx.accounts.application_state;
+        if Stage::from(state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if state.arbiter.is_none() {
+            return Err(ErrorCode::NoArbiter.into());
+        }
+        // On Bob's side the claim belongs to whoever holds the receipt: a receiver who traded it away
+        // has no say anymore, and whoever bought it can dispute in his place.
+        let disputer = ctx.accounts.disputer.key();
+        if disputer != state.user_sending {
+            let receipt_wallet = Account::<TokenAccount>::try_from(&ctx.accounts.receipt_wallet)?;
+            let holds_receipt = receipt_wallet.owner == disputer
+                && receipt_wallet.mint == state.receipt_mint
+                && receipt_wallet.amount == 1;
+            if !holds_receipt {
+                return Err(ErrorCode::NotAParty.into());
+            }
+        }
+
+        // From now on only the arbiter can move the funds.
+        msg!("Dispute raised by {}", disputer);
+        state.stage = Stage::Disputed.to_code();
+        Ok(())
+    }
+
+    pub fn resolve_dispute(ctx: Context<ResolveDispute>, application_idx: u64, state_bump: u8, _wallet_bump: u8, amount_to_receiver: u64) -> ProgramResult {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::Disputed {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if ctx.accounts.application_state.arbiter != Some(ctx.accounts.arbiter.key()) {
+            return Err(ErrorCode::NotArbiter.into());
+        }
+
+        let wallet_amount = ctx.accounts.escrow_wallet_state.amount;
+        if amount_to_receiver > wallet_amount {
+            msg!("Cannot award {} out of {}", amount_to_receiver, wallet_amount);
+            return Err(ErrorCode::InvalidResolution.into());
+        }
+        let amount_to_sender = wallet_amount - amount_to_receiver;
+
+        // Bob's share goes first; whichever transfer empties the escrow also closes it. Like a pull back,
+        // refunding Alice always works, but nobody is paid out while SafePay is paused or anyone involved
+        // is denied. The receipt is only needed to award Bob's side: once it was burned, everything can
+        // still go back to Alice.
+        if amount_to_receiver > 0 {
+            check_not_paused(&ctx.accounts.config)?;
+            check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+            check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+            check_not_denied(&ctx.accounts.beneficiary_denylist_entry, ctx.accounts.beneficiary.key)?;
+            let receipt_wallet = Account::<TokenAccount>::try_from(&ctx.accounts.receipt_wallet)?;
+            let holds_receipt = receipt_wallet.owner == ctx.accounts.beneficiary.key()
+                && receipt_wallet.mint == ctx.accounts.application_state.receipt_mint
+                && receipt_wallet.amount == 1;
+            if !holds_receipt {
+                return Err(ErrorCode::InvalidReceiptWallet.into());
+            }
+
+            // Whatever Bob's side is awarded settles the claim, so the holder co-signs to burn the receipt.
+            if !ctx.accounts.beneficiary.is_signer {
+                return Err(ErrorCode::ReceiptHolderSignatureRequired.into());
+            }
+            let burn_instruction = Burn{
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                to: ctx.accounts.receipt_wallet.to_account_info(),
+                authority: ctx.accounts.beneficiary.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+            anchor_spl::token::burn(cpi_ctx, 1)?;
+
+            let wallet_to_deposit_to = Account::<TokenAccount>::try_from(&ctx.accounts.wallet_to_deposit_to)?;
+            let is_valid_wallet = wallet_to_deposit_to.owner == ctx.accounts.beneficiary.key()
+                && wallet_to_deposit_to.mint == ctx.accounts.mint_of_token_being_sent.key();
+            if !is_valid_wallet {
+                return Err(ErrorCode::InvalidBeneficiaryWallet.into());
+            }
 
-        // The `?` at the end will cause the function to return early in case of an error.
-        // This pattern is common in Rust.
-        anchor_spl::token::transfer(cpi_ctx, state.amount_tokens)?;
+            // The protocol takes its cut of Bob's share only, Alice's refund is never charged.
+            let fee = ctx.accounts.config.fee_for(amount_to_receiver);
+            transfer_escrow_out_with_fee(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.fee_vault.to_account_info(),
+                ctx.accounts.wallet_to_deposit_to.to_account_info(),
+                amount_to_receiver,
+                fee,
+            )?;
+        }
+        if amount_to_sender > 0 {
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                ctx.accounts.mint_of_token_being_sent.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                state_bump,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.refund_wallet.to_account_info(),
+                amount_to_sender,
+            )?;
+        }
+
+        msg!("Dispute resolved: {} to receiver, {} to sender", amount_to_receiver, amount_to_sender);
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::Resolved.to_code();
+        Ok(())
+    }
+
+    pub fn initialize_new_sol_grant(ctx: Context<InitializeNewSolGrant>, application_idx: u64, _state_bump: u8, amount: u64) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        if amount == 0 {
+            return Err(ErrorCode::ZeroAmount.into());
+        }
+
+        let state = &mut ctx.accounts.application_state;
+        state.idx = application_idx;
+        state.user_sending = ctx.accounts.user_sending.key().clone();
+        state.user_receiving = ctx.accounts.user_receiving.key().clone();
+        state.amount_lamports = amount;
+
+        msg!("Initialized new SOL Safe Transfer instance for {}", amount);
+
+        // Unlike SPL tokens there is no separate escrow wallet: Alice's lamports are moved on top of the
+        // rent-exempt balance of the state PDA itself.
+        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+            ctx.accounts.user_sending.key,
+            &state.key(),
+            amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_instruction,
+            &[
+                ctx.accounts.user_sending.to_account_info(),
+                state.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
 
         // Mark stage as deposited.
         state.stage = Stage::FundsDeposited.to_code();
         Ok(())
     }
 
-}
+    pub fn complete_sol_grant(ctx: Context<CompleteSolGrant>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
 
-#[derive(Accounts)]
-#[instruction(instance_bump: u8, wallet_bump: u8)]
-pub struct Initialize<'info> {
-    #[account(
-        seeds=[b"instance".as_ref(), user.key.as_ref()],
-        bump = instance_bump,
-    )]
-    instance: AccountInfo<'info>,
-    #[account(
-        init,
-        payer = user,
-        seeds=[b"wallet".as_ref(), user.key.as_ref(), mint.key().as_ref()],
-        bump = wallet_bump,
-        token::mint = mint,
-        token::authority = instance,
-    )]
-    wallet: Account<'info, TokenAccount>,
-    #[account(mut)]
-    mint: Account<'info, Mint>,
-    user: Signer<'info>,
-    system_program: Program<'info, System>,
-    token_program: Program<'info, Token>,
-    rent: Sysvar<'info, Rent>,
-}
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
 
-// Each state corresponds with a separate transaction and represents different moments in the lifecycle
-// of the app.
-//
-// FundsDeposited -> EscrowComplete
-//                OR
-//                -> PullBackComplete
-//
-#[derive(Clone, Copy, PartialEq)]
-pub enum Stage {
-    // Safe Pay withdrew funds from Alice and deposited them into the escrow wallet
-    FundsDeposited,
+        transfer_lamports_out(
+            ctx.accounts.application_state.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.application_state.amount_lamports,
+        )?;
 
-    // {from FundsDeposited} Bob withdrew the funds from the escrow. We are done.
-    EscrowComplete,
+        // The grant is settled: `close` hands the rent of the state account back to Alice.
+        Ok(())
+    }
 
-    // {from FundsDeposited} Alice pulled back the funds
-    PullBackComplete,
-}
+    pub fn pull_back_sol(ctx: Context<PullBackSolInstruction>, _application_idx: u64, _state_bump: u8) -> ProgramResult {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
 
-impl Stage {
-    fn to_code(&self) -> u8 {
-        match self {
-            Stage::FundsDeposited => 1,
-            Stage::EscrowComplete => 2,
-            Stage::PullBackComplete => 3,
+        transfer_lamports_out(
+            ctx.accounts.application_state.to_account_info(),
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.application_state.amount_lamports,
+        )?;
+
+        // `close` returns the rent of the state account along with the escrowed lamports.
+        Ok(())
+    }
+
+    pub fn expire_grant(ctx: Context<ExpireGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+        // An approved release that Bob never claimed expires like any other grant.
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage != Stage::FundsDeposited && current_stage != Stage::Approved {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
         }
+        if !ctx.accounts.application_state.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ErrorCode::GrantNotExpired.into());
+        }
+
+        // Anyone can crank this: the funds can only ever go back to Alice's wallet, and both the
+        // escrow and the state account are closed with their rent returned to her.
+        let wallet_amount = ctx.accounts.escrow_wallet_state.amount;
+        transfer_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.refund_wallet.to_account_info(),
+            wallet_amount,
+        )?;
+        Ok(())
     }
 
-    fn from(val: u8) -> std::result::Result<Stage, ProgramError> {
-        match val {
-            1 => Ok(Stage::FundsDeposited),
-            2 => Ok(Stage::EscrowComplete),
-            3 => Ok(Stage::PullBackComplete),
-            unknown_value => {
-                msg!("Unknown stage: {}", unknown_value);
-                Err(ErrorCode::StageInvalid.into())
-            }
+    pub fn initialize_milestone_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, milestones: Vec<Milestone>) -> ProgramResult {
+        if milestones.is_empty() || milestones.len() > MAX_MILESTONES {
+            msg!("A milestone grant needs between 1 and {} milestones, got {}", MAX_MILESTONES, milestones.len());
+            return Err(ErrorCode::InvalidMilestones.into());
+        }
+
+        // The sum of all the tranches is what Alice locks up front.
+        let mut amount: u64 = 0;
+        for milestone in milestones.iter() {
+            amount = amount.checked_add(milestone.amount).ok_or(ErrorCode::InvalidMilestones)?;
         }
+
+        // Whatever the client sent, nothing has been released yet.
+        ctx.accounts.application_state.milestones = milestones
+            .into_iter()
+            .map(|m| Milestone { released: false, ..m })
+            .collect();
+
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
     }
-}
 
-// 1 State account instance == 1 Safe Pay instance
-#[account]
-#[derive(Default)]
-pub struct State {
+    pub fn initialize_vesting_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, amount: u64, start_ts: i64, cliff_ts: i64, end_ts: i64) -> ProgramResult {
+        if !(start_ts <= cliff_ts && cliff_ts <= end_ts && start_ts < end_ts) {
+            msg!("Vesting schedule is invalid: start {}, cliff {}, end {}", start_ts, cliff_ts, end_ts);
+            return Err(ErrorCode::InvalidVestingSchedule.into());
+        }
+
+        let state = &mut ctx.accounts.application_state;
+        state.vesting_start_ts = start_ts;
+        state.vesting_cliff_ts = cliff_ts;
+        state.vesting_end_ts = end_ts;
+
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
+    }
+
+    pub fn withdraw_vested(ctx: Context<CompleteGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        check_not_denied(&ctx.accounts.beneficiary_denylist_entry, ctx.accounts.beneficiary.key)?;
+
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PartiallyReleased;
+        if !is_valid_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if !ctx.accounts.application_state.is_vesting() {
+            return Err(ErrorCode::GrantIsNotVesting.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let state = &ctx.accounts.application_state;
+        let withdrawable = state.vested_amount(now) - state.amount_withdrawn;
+        if withdrawable == 0 {
+            return Err(ErrorCode::NothingVested.into());
+        }
+
+        // The protocol takes its cut of every withdrawal.
+        let fee = ctx.accounts.config.fee_for(withdrawable);
+        transfer_escrow_out_with_fee(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            withdrawable,
+            fee,
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.amount_withdrawn += withdrawable;
+        if state.amount_withdrawn < state.amount_tokens {
+            state.stage = Stage::PartiallyReleased.to_code();
+            return Ok(());
+        }
+
+        // Fully streamed: the receipt is spent.
+        let burn_instruction = Burn{
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+        anchor_spl::token::burn(cpi_ctx, 1)?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+        Ok(())
+    }
+
+    // A hash-time-locked grant: Bob claims by revealing the SHA-256 preimage of `hashlock` before
+    // `timeout_ts`. After that the grant expires and Alice can pull back, exactly like the claim window.
+    pub fn initialize_htlc_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, amount: u64, hashlock: [u8; 32], timeout_ts: i64) -> ProgramResult {
+        if timeout_ts <= Clock::get()?.unix_timestamp {
+            msg!("Timeout {} is in the past", timeout_ts);
+            return Err(ErrorCode::InvalidExpiry.into());
+        }
+
+        let state = &mut ctx.accounts.application_state;
+        state.hashlock = Some(hashlock);
+        state.expiry_ts = timeout_ts;
+        state.guaranteed_until_ts = timeout_ts;
+
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
+    }
+
+    // A claim link grant is escrowed against an ephemeral key that stands in for Bob (`user_receiving`).
+    // Alice shares the matching secret off-chain, and whoever holds it can redeem the funds to any wallet.
+    pub fn initialize_claim_link_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, amount: u64) -> ProgramResult {
+        ctx.accounts.application_state.is_claim_link = true;
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
+    }
+
+    pub fn claim_link(ctx: Context<ClaimLink>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        check_not_denied(&ctx.accounts.destination_denylist_entry, ctx.accounts.destination_owner.key)?;
+
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if !ctx.accounts.application_state.is_claim_link {
+            return Err(ErrorCode::InvalidClaimSignature.into());
+        }
+
+        // The ephemeral key must have signed the destination. Binding the signature to the destination
+        // means a copied signature can only ever pay out to the wallet its holder chose.
+        verify_ed25519_signature(
+            &ctx.accounts.instructions,
+            ctx.accounts.user_receiving.key,
+            ctx.accounts.destination_owner.key.as_ref(),
+        )?;
+
+        let amount = ctx.accounts.application_state.amount_tokens;
+        let fee = ctx.accounts.config.fee_for(amount);
+        transfer_escrow_out_with_fee(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            amount,
+            fee,
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+        Ok(())
+    }
+
+    // A swap grant: Alice escrows `amount` of her mint and asks for `amount_to_receive` of `mint_to_receive`
+    // in exchange. Bob settles both legs atomically with `complete_swap`.
+    pub fn initialize_swap_grant(ctx: Context<InitializeNewGrant>, application_idx: u64, state_bump: u8, _wallet_bump: u8, _receipt_bump: u8, amount: u64, mint_to_receive: Pubkey, amount_to_receive: u64) -> ProgramResult {
+        let state = &mut ctx.accounts.application_state;
+        state.mint_to_receive = Some(mint_to_receive);
+        state.amount_to_receive = amount_to_receive;
+
+        deposit_into_escrow(ctx.accounts, application_idx, state_bump, amount)
+    }
+
+    pub fn complete_swap(ctx: Context<CompleteSwap>, application_idx: u64, state_bump: u8, _wallet_bump: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        check_not_denied(&ctx.accounts.beneficiary_denylist_entry, ctx.accounts.beneficiary.key)?;
+
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if ctx.accounts.application_state.mint_to_receive != Some(ctx.accounts.mint_to_receive.key()) {
+            return Err(ErrorCode::GrantIsNotSwap.into());
+        }
+
+        // The receipt holder's leg: they sign the transaction, so no PDA seeds are needed.
+        transfer_checked(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.wallet_to_pay_from.to_account_info(),
+            ctx.accounts.mint_to_receive.to_account_info(),
+            ctx.accounts.wallet_to_receive_in.to_account_info(),
+            ctx.accounts.beneficiary.to_account_info(),
+            &[],
+            ctx.accounts.application_state.amount_to_receive,
+            ctx.accounts.mint_to_receive.decimals,
+        )?;
+
+        // Alice's leg comes out of the escrow, minus the protocol fee. If either leg fails, the whole
+        // transaction is rolled back.
+        let amount = ctx.accounts.application_state.amount_tokens;
+        let fee = ctx.accounts.config.fee_for(amount);
+        transfer_escrow_out_with_fee(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            amount,
+            fee,
+        )?;
+
+        // The claim is spent: burn the receipt so it can't be traded anymore.
+        let burn_instruction = Burn{
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+        anchor_spl::token::burn(cpi_ctx, 1)?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+        Ok(())
+    }
+
+    pub fn increase_grant(ctx: Context<IncreaseGrant>, _application_idx: u64, _state_bump: u8, _wallet_bump: u8, amount: u64) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // The tranches of a milestone grant must always add up to its amount.
+        if !ctx.accounts.application_state.milestones.is_empty() {
+            return Err(ErrorCode::GrantHasMilestones.into());
+        }
+
+        // The top-up itself can't be empty, and the grant must stay within the limits of its mint.
+        if amount == 0 {
+            return Err(ErrorCode::ZeroAmount.into());
+        }
+        let new_amount = ctx.accounts.application_state.amount_tokens.checked_add(amount).ok_or(ProgramError::InvalidArgument)?;
+        ctx.accounts.allowed_mint.check_amount(new_amount)?;
+
+        // Alice signs the transaction, so no PDA seeds are needed.
+        transfer_checked(
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            ctx.accounts.escrow_wallet_state.to_account_info(),
+            ctx.accounts.user_sending.to_account_info(),
+            &[],
+            amount,
+            ctx.accounts.mint_of_token_being_sent.decimals,
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.amount_tokens = new_amount;
+
+        emit!(GrantIncreased {
+            application_state: state.key(),
+            delta: amount,
+            amount_tokens: state.amount_tokens,
+        });
+        Ok(())
+    }
+
+    pub fn release_milestone(ctx: Context<ReleaseMilestone>, application_idx: u64, state_bump: u8, _wallet_bump: u8, milestone_idx: u8) -> ProgramResult {
+        check_not_paused(&ctx.accounts.config)?;
+        check_not_denied(&ctx.accounts.sender_denylist_entry, ctx.accounts.user_sending.key)?;
+        check_not_denied(&ctx.accounts.receiver_denylist_entry, ctx.accounts.user_receiving.key)?;
+        check_not_denied(&ctx.accounts.beneficiary_denylist_entry, ctx.accounts.beneficiary.key)?;
+
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        let is_valid_stage = current_stage == Stage::FundsDeposited || current_stage == Stage::PartiallyReleased;
+        if !is_valid_stage {
+            msg!("Stage is invalid, state stage is {}", ctx.accounts.application_state.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        let milestone_amount = match ctx.accounts.application_state.milestones.get(milestone_idx as usize) {
+            Some(milestone) if !milestone.released => milestone.amount,
+            Some(_) => return Err(ErrorCode::MilestoneAlreadyReleased.into()),
+            None => return Err(ErrorCode::InvalidMilestones.into()),
+        };
+
+        // The protocol takes its cut of every tranche.
+        let fee = ctx.accounts.config.fee_for(milestone_amount);
+        transfer_escrow_out_with_fee(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            state_bump,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            milestone_amount,
+            fee,
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.milestones[milestone_idx as usize].released = true;
+        if !state.milestones.iter().all(|m| m.released) {
+            state.stage = Stage::PartiallyReleased.to_code();
+            return Ok(());
+        }
+
+        // The last tranche spends the claim. Only the holder can burn the receipt, so they co-sign it.
+        if !ctx.accounts.beneficiary.is_signer {
+            return Err(ErrorCode::ReceiptHolderSignatureRequired.into());
+        }
+        let burn_instruction = Burn{
+            mint: ctx.accounts.receipt_mint.to_account_info(),
+            to: ctx.accounts.receipt_wallet.to_account_info(),
+            authority: ctx.accounts.beneficiary.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), burn_instruction);
+        anchor_spl::token::burn(cpi_ctx, 1)?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+        Ok(())
+    }
+
+
+    // The config is a singleton. Only the upgrade authority of the program can create it, and becomes
+    // its first admin, so nobody can front-run the deployment.
+    pub fn initialize_config(ctx: Context<InitializeConfig>, config_bump: u8, fee_bps: u16, fee_recipient: Pubkey, max_integrator_fee_bps: u16) -> ProgramResult {
+        check_upgrade_authority(&ctx.accounts.program_data, ctx.accounts.admin.key)?;
+
+        // Both fees together can never exceed the grant.
+        if fee_bps as u32 + max_integrator_fee_bps as u32 > MAX_FEE_BPS as u32 {
+            return Err(ErrorCode::InvalidFee.into());
+        }
+
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.fee_bps = fee_bps;
+        config.fee_recipient = fee_recipient;
+        config.max_integrator_fee_bps = max_integrator_fee_bps;
+        config.bump = config_bump;
+        Ok(())
+    }
+
+    // Lowering `max_integrator_fee_bps` only applies to new grants, open grants keep the fee they were created with.
+    // A new `fee_bps` applies to open grants too, capped at whatever the integrator fee leaves of the grant.
+    pub fn update_config(ctx: Context<UpdateConfig>, fee_bps: u16, fee_recipient: Pubkey, max_integrator_fee_bps: u16) -> ProgramResult {
+        if fee_bps as u32 + max_integrator_fee_bps as u32 > MAX_FEE_BPS as u32 {
+            return Err(ErrorCode::InvalidFee.into());
+        }
+
+        let config = &mut ctx.accounts.config;
+        config.fee_bps = fee_bps;
+        config.fee_recipient = fee_recipient;
+        config.max_integrator_fee_bps = max_integrator_fee_bps;
+        Ok(())
+    }
+
+    pub fn set_paused(ctx: Context<UpdateConfig>, paused: bool) -> ProgramResult {
+        ctx.accounts.config.paused = paused;
+        msg!("SafePay paused: {}", paused);
+        Ok(())
+    }
+
+    pub fn transfer_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> ProgramResult {
+        ctx.accounts.config.admin = new_admin;
+        Ok(())
+    }
+
+    pub fn allow_mint(ctx: Context<AllowMint>, allowed_mint_bump: u8, min_amount: u64, max_amount: u64) -> ProgramResult {
+        if min_amount > max_amount {
+            return Err(ErrorCode::InvalidAmountLimits.into());
+        }
+
+        let allowed_mint = &mut ctx.accounts.allowed_mint;
+        allowed_mint.mint = ctx.accounts.mint.key();
+        allowed_mint.min_amount = min_amount;
+        allowed_mint.max_amount = max_amount;
+        allowed_mint.bump = allowed_mint_bump;
+        Ok(())
+    }
+
+    // New limits only apply to new grants and top-ups, open grants are left as they are.
+    pub fn set_mint_limits(ctx: Context<SetMintLimits>, min_amount: u64, max_amount: u64) -> ProgramResult {
+        if min_amount > max_amount {
+            return Err(ErrorCode::InvalidAmountLimits.into());
+        }
+
+        let allowed_mint = &mut ctx.accounts.allowed_mint;
+        allowed_mint.min_amount = min_amount;
+        allowed_mint.max_amount = max_amount;
+        Ok(())
+    }
+
+    // Open grants in the mint are unaffected, they can still be claimed or pulled back.
+    pub fn disallow_mint(_ctx: Context<DisallowMint>) -> ProgramResult {
+        Ok(())
+    }
+
+    pub fn deny_address(ctx: Context<DenyAddress>, denied_address_bump: u8) -> ProgramResult {
+        let denied_address = &mut ctx.accounts.denied_address;
+        denied_address.address = ctx.accounts.address.key();
+        denied_address.bump = denied_address_bump;
+        Ok(())
+    }
+
+    pub fn undeny_address(_ctx: Context<UndenyAddress>) -> ProgramResult {
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(instance_bump: u8, wallet_bump: u8)]
+pub struct Initialize<'info> {
+    #[account(
+        seeds=[b"instance".as_ref(), user.key.as_ref()],
+        bump = instance_bump,
+    )]
+    instance: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = user,
+        seeds=[b"wallet".as_ref(), user.key.as_ref(), mint.key().as_ref()],
+        bump = wallet_bump,
+        token::mint = mint,
+        token::authority = instance,
+    )]
+    wallet: Account<'info, TokenAccount>,
+    #[account(mut)]
+    mint: Account<'info, Mint>,
+    user: Signer<'info>,
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+}
+
+// Each state corresponds with a separate transaction and represents different moments in the lifecycle
+// of the app.
+//
+// FundsDeposited -> EscrowComplete
+//                OR
+//                -> PullBackComplete
+//                OR
+//                -> PartiallyReleased (milestones / vesting) -> EscrowComplete
+//                                     OR
+//                                     -> PullBackComplete
+//                OR
+//                -> Disputed -> Resolved
+//                OR
+//                -> Approved -> EscrowComplete
+//                OR
+//                -> Rejected (also from Approved)
+//
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stage {
+    // Safe Pay withdrew funds from Alice and deposited them into the escrow wallet
+    FundsDeposited,
+
+    // {from FundsDeposited} Bob withdrew the funds from the escrow. We are done.
+    EscrowComplete,
+
+    // {from FundsDeposited, PartiallyReleased} Alice pulled back the funds
+    PullBackComplete,
+
+    // {from FundsDeposited} Alice released some, but not all, of the milestones to Bob,
+    // or Bob withdrew some, but not all, of his vested tokens
+    PartiallyReleased,
+
+    // {from FundsDeposited} Alice or the receipt holder raised a dispute. Funds are frozen until the arbiter steps in.
+    Disputed,
+
+    // {from Disputed} The arbiter split the escrow between Alice and Bob. We are done.
+    Resolved,
+
+    // {from FundsDeposited} The approver signed off on the release, Bob can now claim the funds.
+    Approved,
+
+    // {from FundsDeposited, Approved} Bob declined the grant and the funds went back to Alice. We are done.
+    Rejected,
+}
+
+impl Stage {
+    fn to_code(&self) -> u8 {
+        match self {
+            Stage::FundsDeposited => 1,
+            Stage::EscrowComplete => 2,
+            Stage::PullBackComplete => 3,
+            Stage::PartiallyReleased => 4,
+            Stage::Disputed => 5,
+            Stage::Resolved => 6,
+            Stage::Approved => 7,
+            Stage::Rejected => 8,
+        }
+    }
+
+    // Stages after which no funds are left in the escrow.
+    fn is_terminal(&self) -> bool {
+        matches!(self, Stage::EscrowComplete | Stage::PullBackComplete | Stage::Resolved | Stage::Rejected)
+    }
+
+    fn from(val: u8) -> std::result::Result<Stage, ProgramError> {
+        match val {
+            1 => Ok(Stage::FundsDeposited),
+            2 => Ok(Stage::EscrowComplete),
+            3 => Ok(Stage::PullBackComplete),
+            4 => Ok(Stage::PartiallyReleased),
+            5 => Ok(Stage::Disputed),
+            6 => Ok(Stage::Resolved),
+            7 => Ok(Stage::Approved),
+            8 => Ok(Stage::Rejected),
+            unknown_value => {
+                msg!("Unknown stage: {}", unknown_value);
+                Err(ErrorCode::StageInvalid.into())
+            }
+        }
+    }
+}
+
+// 1 State account instance == 1 Safe Pay instance
+#[account]
+#[derive(Default)]
+pub struct State {
+
+    // A primary key that allows us to derive other important accounts
+    idx: u64,
+    
+    // Alice
+    user_sending: Pubkey,
+
+    // Bob
+    user_receiving: Pubkey,
+
+    // The Mint of the token that Alice wants to send to Bob
+    mint_of_token_being_sent: Pubkey,
+
+    // The escrow wallet
+    escrow_wallet: Pubkey,
+
+    // The amount of tokens Alice wants to send to Bob
+    amount_tokens: u64,
+
+    // An enumm that is to represent some kind of state machine
+    stage: u8,
+
+    // The tranches Bob gets paid in. Empty for a grant that is released in one shot.
+    milestones: Vec<Milestone>,
+
+    // Vesting schedule (unix timestamps). All zero unless this is a vesting grant.
+    vesting_start_ts: i64,
+    vesting_cliff_ts: i64,
+    vesting_end_ts: i64,
+
+    // The amount of tokens Bob already withdrew from a vesting grant
+    amount_withdrawn: u64,
+
+    // Unix timestamp after which Bob can no longer claim the grant. Zero means it never expires.
+    expiry_ts: i64,
+
+    // Unix timestamp until which Alice cannot pull back, guaranteeing Bob a window to claim.
+    // Zero means Alice can pull back at any time.
+    guaranteed_until_ts: i64,
+
+    // A third party that can split the escrow between Alice and the receipt holder if either raises a dispute
+    arbiter: Option<Pubkey>,
+
+    // If set, Bob can only claim after this key (usually Alice) approved the release
+    approver: Option<Pubkey>,
+
+    // SHA-256 hash of the secret Bob must reveal to claim an HTLC grant
+    hashlock: Option<[u8; 32]>,
+
+    // Whether `user_receiving` is the ephemeral key of a claim link rather than Bob himself
+    is_claim_link: bool,
+
+    // For swap grants, the mint Alice expects in exchange and how much of it
+    mint_to_receive: Option<Pubkey>,
+    amount_to_receive: u64,
+
+    // The mint of the one-of-one receipt token representing the right to claim. Whoever holds the
+    // receipt is the beneficiary of the grant.
+    receipt_mint: Pubkey,
+
+    // The platform that embedded SafePay, if any, and its share of the grant in basis points
+    integrator: Option<Pubkey>,
+    integrator_fee_bps: u16,
+}
+
+impl State {
+    // Anchor sizes `init` accounts from `State::default()`, which would leave no room for milestones.
+    pub const LEN: usize = 8 + 8 + 32 * 4 + 8 + 1 + (4 + MAX_MILESTONES * Milestone::LEN) + 8 * 3 + 8 + 8 + 8 + (1 + 32) + (1 + 32) + (1 + 32) + 1 + (1 + 32) + 8 + 32 + (1 + 32) + 2;
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expiry_ts != 0 && now >= self.expiry_ts
+    }
+
+    fn is_vesting(&self) -> bool {
+        self.vesting_end_ts != 0
+    }
+
+    // The amount of tokens vested at `now`, whether or not Bob already withdrew them.
+    // Nothing vests before the cliff, then tokens unlock linearly from the start until the end.
+    fn vested_amount(&self, now: i64) -> u64 {
+        if now < self.vesting_cliff_ts {
+            return 0;
+        }
+        if now >= self.vesting_end_ts {
+            return self.amount_tokens;
+        }
+        let elapsed = (now - self.vesting_start_ts) as u128;
+        let duration = (self.vesting_end_ts - self.vesting_start_ts) as u128;
+        (self.amount_tokens as u128 * elapsed / duration) as u64
+    }
+
+    // The integrator's share of the grant, rounded down.
+    fn integrator_fee(&self) -> u64 {
+        bps_of(self.amount_tokens, self.integrator_fee_bps)
+    }
+}
+
+// 1 SolState account instance == 1 Safe Pay instance denominated in native SOL.
+// The escrowed lamports are held by the account itself, on top of its rent-exempt balance.
+#[account]
+#[derive(Default)]
+pub struct SolState {
+
+    // A primary key that allows us to derive other important accounts
+    idx: u64,
+
+    // Alice
+    user_sending: Pubkey,
+
+    // Bob
+    user_receiving: Pubkey,
+
+    // The amount of lamports Alice wants to send to Bob
+    amount_lamports: u64,
+
+    // An enumm that is to represent some kind of state machine, shared with SPL grants
+    stage: u8,
+}
+
+// Emitted every time Alice tops up a grant
+#[event]
+pub struct GrantIncreased {
+    pub application_state: Pubkey,
+    pub delta: u64,
+    pub amount_tokens: u64,
+}
+
+// The maximum number of tranches a single grant can be split into.
+pub const MAX_MILESTONES: usize = 10;
+
+// A single tranche of a milestone grant
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct Milestone {
+
+    // The amount of tokens released to Bob once the milestone is approved
+    pub amount: u64,
+
+    // A hash of the off-chain description of the work being paid for
+    pub description_hash: [u8; 32],
+
+    // Whether Alice already released this tranche
+    pub released: bool,
+}
+
+impl Milestone {
+    pub const LEN: usize = 8 + 32 + 1;
+}
+
+// The maximum number of approvers in a multisig release. Approvals are tracked in a `u16` bitmap.
+pub const MAX_APPROVERS: usize = 16;
+
+// The M-of-N approvers of a grant, stored in a PDA next to its `State`
+#[account]
+#[derive(Default)]
+pub struct ApproverSet {
+
+    // The N keys allowed to approve the release
+    approvers: Vec<Pubkey>,
+
+    // The M approvals needed before Bob can claim
+    threshold: u8,
+
+    // Bit `i` is set once `approvers[i]` approved
+    approvals: u16,
+}
+
+impl ApproverSet {
+    pub const LEN: usize = 8 + (4 + MAX_APPROVERS * 32) + 1 + 2;
+}
+
+// A fee of 10_000 basis points would hand the whole grant to the protocol.
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+// `bps` basis points of `amount`, rounded down.
+fn bps_of(amount: u64, bps: u16) -> u64 {
+    (amount as u128 * bps as u128 / 10_000) as u64
+}
+
+// The protocol-wide settings, stored in a single PDA
+#[account]
+#[derive(Default)]
+pub struct Config {
+
+    // The only key allowed to update the config
+    admin: Pubkey,
+
+    // The fee taken on every payout to the receipt holder, in basis points. The vested share Alice pays out
+    // along with a pull back is the exception: it settles her refund rather than a claim.
+    fee_bps: u16,
+
+    // The owner of the Token accounts that collect the fees
+    fee_recipient: Pubkey,
+
+    // The highest fee an integrator can set on a grant, in basis points
+    max_integrator_fee_bps: u16,
+
+    // Emergency switch: while set, no grant is opened, topped up, reassigned or paid out. Refunds to
+    // Alice (pull backs, rejections, expiries and dispute refunds) stay open, along with the vested
+    // share a pull back settles on the way
+    paused: bool,
+
+    // The bump of the config PDA, so instructions don't need to pass it in
+    bump: u8,
+}
+
+// A mint approved for grants. One PDA per mint, seeded by the mint key.
+#[account]
+#[derive(Default)]
+pub struct AllowedMint {
+
+    // The approved mint
+    mint: Pubkey,
+
+    // The smallest and largest grant allowed in this mint, in its base units
+    min_amount: u64,
+    max_amount: u64,
+
+    // The bump of the PDA
+    bump: u8,
+}
+
+impl AllowedMint {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 1;
+
+    // Zero is never a valid grant, whatever the limits of the mint say.
+    fn check_amount(&self, amount: u64) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::ZeroAmount.into());
+        }
+        if amount < self.min_amount {
+            msg!("Amount {} is below the minimum of {}", amount, self.min_amount);
+            return Err(ErrorCode::AmountBelowMinimum.into());
+        }
+        if amount > self.max_amount {
+            msg!("Amount {} is above the maximum of {}", amount, self.max_amount);
+            return Err(ErrorCode::AmountAboveMaximum.into());
+        }
+        Ok(())
+    }
+}
+
+// A blocked address. One PDA per address, seeded by the address itself: grants can't be created or
+// claimed while either party has an entry, but Alice can still pull back.
+#[account]
+#[derive(Default)]
+pub struct DeniedAddress {
+
+    // The blocked address
+    address: Pubkey,
+
+    // The bump of the PDA
+    bump: u8,
+}
+
+impl DeniedAddress {
+    pub const LEN: usize = 8 + 32 + 1;
+}
+
+impl Config {
+    pub const LEN: usize = 8 + 32 + 2 + 32 + 2 + 1 + 1;
+
+    // The protocol's share of `amount`, rounded down.
+    fn fee_for(&self, amount: u64) -> u64 {
+        bps_of(amount, self.fee_bps)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8, receipt_bump: u8)]
+pub struct InitializeNewGrant<'info> {
+
+    // Derived PDAs
+    #[account(
+        init,
+        payer = user_sending,
+        space = State::LEN,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+        token::mint=mint_of_token_being_sent,
+        token::authority=application_state,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    // The one-of-one receipt of the grant, minted to Bob
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"receipt".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = receipt_bump,
+        mint::decimals = 0,
+        mint::authority = application_state,
+    )]
+    receipt_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = user_sending,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user_receiving,
+    )]
+    receipt_wallet: Account<'info, TokenAccount>,
+
+    // The protocol settings, for the integrator fee cap and the pause switch
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+    )]
+    config: Account<'info, Config>,
+
+    // The allowlist entry of `mint_of_token_being_sent` and its amount limits, checked in `deposit_into_escrow`
+    allowed_mint: AccountInfo<'info>,
+
+    // The denylist PDAs of Alice and Bob, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,              // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,  // USDC
+
+    // Alice's USDC wallet that has already approved the escrow wallet
+    #[account(
+        mut,
+        constraint=wallet_to_withdraw_from.owner == user_sending.key(),
+        constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key()
+    )]
+    wallet_to_withdraw_from: Account<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct CompleteGrant<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = beneficiary,
+    )]
+    wallet_to_deposit_to: Account<'info, TokenAccount>,   // The beneficiary's USDC wallet (will be initialized if it did not exist)
+
+    // The receipt of the grant. Whoever holds it can claim, even if it was traded outside of `transfer_claim`.
+    #[account(mut)]
+    receipt_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint=receipt_wallet.owner == beneficiary.key(),
+        constraint=receipt_wallet.mint == receipt_mint.key(),
+        constraint=receipt_wallet.amount == 1
+    )]
+    receipt_wallet: Account<'info, TokenAccount>,
+
+    // The protocol fee settings, and the fee recipient's USDC wallet (will be initialized if it did not exist)
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = fee_recipient,
+    )]
+    config: Account<'info, Config>,
+    fee_recipient: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = fee_recipient,
+    )]
+    fee_vault: Account<'info, TokenAccount>,
+
+    // The integrator's USDC wallet. Only read when the grant has an integrator, pass any account otherwise.
+    #[account(mut)]
+    integrator_wallet: AccountInfo<'info>,
+
+    // The denylist PDAs of Alice, Bob and the receipt holder, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    beneficiary_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    #[account(mut)]
+    beneficiary: Signer<'info>,                           // The holder of the receipt
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct PullBackInstruction<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,    
+    // The denylist PDAs of Alice, Bob and the receipt holder. Only read when paying out what vested, pass them anyway.
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    beneficiary_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,
+    user_receiving: AccountInfo<'info>,
+    mint_of_token_being_sent: Account<'info, Mint>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    rent: Sysvar<'info, Rent>,
+
+    // The receipt of the grant. Its holder is owed whatever already vested when pulling back a vesting grant.
+    receipt_mint: Account<'info, Mint>,
+    receipt_wallet: AccountInfo<'info>,
+
+    // The beneficiary's USDC wallet, receives whatever already vested when pulling back a vesting grant.
+    // It and `receipt_wallet` are only read for vesting grants, so any account can be passed otherwise.
+    // Alice doesn't pay to create it: the beneficiary's wallet must already exist when something vested.
+    #[account(mut)]
+    wallet_to_deposit_to: AccountInfo<'info>,
+
+    // Wallet to deposit to
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key(),
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+    )]
+    refund_wallet: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct ReleaseMilestone<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = beneficiary,
+    )]
+    wallet_to_deposit_to: Account<'info, TokenAccount>,   // The beneficiary's USDC wallet (Alice pays for it if it did not exist)
+
+    // The receipt of the grant: whoever holds it is the beneficiary. Burned with the last tranche.
+    #[account(mut)]
+    receipt_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint=receipt_wallet.owner == beneficiary.key(),
+        constraint=receipt_wallet.mint == receipt_mint.key(),
+        constraint=receipt_wallet.amount == 1
+    )]
+    receipt_wallet: Account<'info, TokenAccount>,
+
+    // The protocol fee settings, and the fee recipient's USDC wallet (Alice pays for it if it did not exist)
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = fee_recipient,
+    )]
+    config: Account<'info, Config>,
+    fee_recipient: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = fee_recipient,
+    )]
+    fee_vault: Account<'info, TokenAccount>,
+
+    // The denylist PDAs of Alice, Bob and the receipt holder, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    beneficiary_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    beneficiary: AccountInfo<'info>,                      // The holder of the receipt, signs the last tranche
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct ExpireGrant<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        close = user_sending,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    // Users and accounts in the system. Nobody needs to sign: expiring a grant is permissionless.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+
+    // Application level accounts
+    token_program: Program<'info, Token>,
+
+    // Alice's wallet, where the funds are returned
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key(),
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+    )]
+    refund_wallet: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct RaiseDispute<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+    )]
+    application_state: Account<'info, State>,
+
+    // Users and accounts in the system
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    disputer: Signer<'info>,                              // Alice or the receipt holder
+
+    // The disputer's receipt wallet. Only read when the disputer isn't Alice, pass any account otherwise.
+    receipt_wallet: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct ResolveDispute<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    // The receipt of the grant, burned when Bob's side is awarded something. The holder's receipt wallet
+    // and USDC wallet are only read then, so a burned receipt doesn't lock Alice's share: pass any
+    // account otherwise. The arbiter doesn't pay to create the USDC wallet, it must already exist.
+    #[account(mut)]
+    receipt_mint: Account<'info, Mint>,
+    #[account(mut)]
+    receipt_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    wallet_to_deposit_to: AccountInfo<'info>,
+
+    // Alice's wallet, receives her share of the escrow
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key(),
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+    )]
+    refund_wallet: Account<'info, TokenAccount>,
+
+    // The protocol fee settings, and the fee recipient's USDC wallet (the arbiter pays for it if it did not exist)
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = fee_recipient,
+    )]
+    config: Account<'info, Config>,
+    fee_recipient: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = arbiter,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = fee_recipient,
+    )]
+    fee_vault: Account<'info, TokenAccount>,
+
+    // The denylist PDAs of Alice, Bob and the receipt holder, which must be empty to award Bob anything
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    beneficiary_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    beneficiary: AccountInfo<'info>,                      // The holder of the receipt, signs when awarded anything
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    #[account(mut)]
+    arbiter: Signer<'info>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct ApproveRelease<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+    )]
+    application_state: Account<'info, State>,
+
+    // Users and accounts in the system
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    approver: Signer<'info>,                              // Alice, or whoever she designated
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, approvals_bump: u8)]
+pub struct InitializeApproverSet<'info> {
+    #[account(
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        init,
+        payer = user_sending,
+        space = ApproverSet::LEN,
+        seeds=[b"approvals".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = approvals_bump,
+    )]
+    approver_set: Account<'info, ApproverSet>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, approvals_bump: u8)]
+pub struct Approve<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"approvals".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = approvals_bump,
+    )]
+    approver_set: Account<'info, ApproverSet>,
+
+    // Users and accounts in the system
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    approver: Signer<'info>,                              // One of the N approvers
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct InitializeNewSolGrant<'info> {
+
+    // Derived PDAs
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"sol_state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+    )]
+    application_state: Account<'info, SolState>,
+
+    // The protocol settings, no SOL grant is opened while SafePay is paused
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+    )]
+    config: Account<'info, Config>,
+
+    // The denylist PDAs of Alice and Bob, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,              // Bob
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct CompleteSolGrant<'info> {
+    #[account(
+        mut,
+        seeds=[b"sol_state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        close = user_sending,
+    )]
+    application_state: Account<'info, SolState>,
+
+    // The protocol settings, Bob can't claim while SafePay is paused
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+    )]
+    config: Account<'info, Config>,
+
+    // The denylist PDAs of Alice and Bob, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    #[account(mut)]
+    user_receiving: Signer<'info>,                        // Bob
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct PullBackSolInstruction<'info> {
+    #[account(
+        mut,
+        seeds=[b"sol_state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        close = user_sending,
+    )]
+    application_state: Account<'info, SolState>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct ClaimLink<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = destination_owner,
+    )]
+    wallet_to_deposit_to: Account<'info, TokenAccount>,   // The claimer's USDC wallet (will be initialized if it did not exist)
+
+    // The protocol fee settings, and the fee recipient's USDC wallet (will be initialized if it did not exist)
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = fee_recipient,
+    )]
+    config: Account<'info, Config>,
+    fee_recipient: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = fee_recipient,
+    )]
+    fee_vault: Account<'info, TokenAccount>,
+
+    // The denylist PDAs of Alice, the claim link key and the destination, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    destination_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // The ephemeral claim link key
+    destination_owner: AccountInfo<'info>,                // Whoever redeemed the link
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    #[account(mut)]
+    payer: Signer<'info>,                                 // Pays the fees, usually the claimer or a relayer
+
+    // Application level accounts
+    #[account(constraint = instructions.key() == anchor_lang::solana_program::sysvar::instructions::ID)]
+    instructions: AccountInfo<'info>,
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
+pub struct CompleteSwap<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = beneficiary,
+    )]
+    wallet_to_deposit_to: Account<'info, TokenAccount>,   // The beneficiary's USDC wallet (will be initialized if it did not exist)
 
-    // A primary key that allows us to derive other important accounts
-    idx: u64,
-    
-    // Alice
-    user_sending: Pubkey,
+    // The beneficiary's wallet of the mint Alice asked for
+    #[account(
+        mut,
+        constraint=wallet_to_pay_from.owner == beneficiary.key(),
+        constraint=wallet_to_pay_from.mint == mint_to_receive.key()
+    )]
+    wallet_to_pay_from: Account<'info, TokenAccount>,
 
-    // Bob
-    user_receiving: Pubkey,
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = mint_to_receive,
+        associated_token::authority = user_sending,
+    )]
+    wallet_to_receive_in: Account<'info, TokenAccount>,   // Alice's wallet of the mint she asked for (will be initialized if it did not exist)
 
-    // The Mint of the token that Alice wants to send to Bob
-    mint_of_token_being_sent: Pubkey,
+    // The receipt of the grant. Whoever holds it takes Bob's side of the swap.
+    #[account(mut)]
+    receipt_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint=receipt_wallet.owner == beneficiary.key(),
+        constraint=receipt_wallet.mint == receipt_mint.key(),
+        constraint=receipt_wallet.amount == 1
+    )]
+    receipt_wallet: Account<'info, TokenAccount>,
 
-    // The escrow wallet
-    escrow_wallet: Pubkey,
+    // The protocol fee settings, and the fee recipient's USDC wallet (will be initialized if it did not exist)
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = fee_recipient,
+    )]
+    config: Account<'info, Config>,
+    fee_recipient: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = fee_recipient,
+    )]
+    fee_vault: Account<'info, TokenAccount>,
 
-    // The amount of tokens Alice wants to send to Bob
-    amount_tokens: u64,
+    // The denylist PDAs of Alice, Bob and the receipt holder, which must be empty
+    sender_denylist_entry: AccountInfo<'info>,
+    receiver_denylist_entry: AccountInfo<'info>,
+    beneficiary_denylist_entry: AccountInfo<'info>,
 
-    // An enumm that is to represent some kind of state machine
-    stage: u8,
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    #[account(mut)]
+    beneficiary: Signer<'info>,                           // The holder of the receipt
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+    mint_to_receive: Account<'info, Mint>,                // The mint Alice wants in exchange
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 #[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
-pub struct InitializeNewGrant<'info> {
-
-    // Derived PDAs
+pub struct IncreaseGrant<'info> {
     #[account(
-        init,
-        payer = user_sending,
+        mut,
         seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
         bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
     )]
     application_state: Account<'info, State>,
     #[account(
-        init,
-        payer = user_sending,
+        mut,
         seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
         bump = wallet_bump,
-        token::mint=mint_of_token_being_sent,
-        token::authority=application_state,
     )]
     escrow_wallet_state: Account<'info, TokenAccount>,
 
+    // The protocol settings, Alice can't top up while SafePay is paused
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+    )]
+    config: Account<'info, Config>,
+
     // Users and accounts in the system
-    #[account(mut)]
-    user_sending: Signer<'info>,                     // Alice
-    user_receiving: AccountInfo<'info>,              // Bob
-    mint_of_token_being_sent: Account<'info, Mint>,  // USDC
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
 
-    // Alice's USDC wallet that has already approved the escrow wallet
+    // Alice's USDC wallet the top-up is taken from
     #[account(
         mut,
         constraint=wallet_to_withdraw_from.owner == user_sending.key(),
@@ -342,15 +2881,20 @@ pub struct InitializeNewGrant<'info> {
     )]
     wallet_to_withdraw_from: Account<'info, TokenAccount>,
 
+    // The allowlist entry of the mint, for its amount limits
+    #[account(
+        seeds=[b"allowed_mint".as_ref(), mint_of_token_being_sent.key().as_ref()],
+        bump = allowed_mint.bump,
+    )]
+    allowed_mint: Account<'info, AllowedMint>,
+
     // Application level accounts
-    system_program: Program<'info, System>,
     token_program: Program<'info, Token>,
-    rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 #[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
-pub struct CompleteGrant<'info> {
+pub struct RejectGrant<'info> {
     #[account(
         mut,
         seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
@@ -358,6 +2902,8 @@ pub struct CompleteGrant<'info> {
         has_one = user_sending,
         has_one = user_receiving,
         has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
+        close = user_sending,
     )]
     application_state: Account<'info, State>,
     #[account(
@@ -367,19 +2913,130 @@ pub struct CompleteGrant<'info> {
     )]
     escrow_wallet_state: Account<'info, TokenAccount>,
 
+    // The receipt of the grant, burned along with the claim
+    #[account(mut)]
+    receipt_mint: Account<'info, Mint>,
     #[account(
-        init_if_needed,
-        payer = user_receiving,
-        associated_token::mint = mint_of_token_being_sent,
-        associated_token::authority = user_receiving,
+        mut,
+        constraint=receipt_wallet.owner == beneficiary.key(),
+        constraint=receipt_wallet.mint == receipt_mint.key(),
+        constraint=receipt_wallet.amount == 1
     )]
-    wallet_to_deposit_to: Account<'info, TokenAccount>,   // Bob's USDC wallet (will be initialized if it did not exist)
+    receipt_wallet: Account<'info, TokenAccount>,
 
     // Users and accounts in the system
     #[account(mut)]
     user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    beneficiary: Signer<'info>,                           // The holder of the receipt
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+
+    // Application level accounts
+    token_program: Program<'info, Token>,
+
+    // Alice's wallet, where the funds are returned
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key(),
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+    )]
+    refund_wallet: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct CloseGrant<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        close = user_sending,
+    )]
+    application_state: Account<'info, State>,
+
+    // Users and accounts in the system
     #[account(mut)]
-    user_receiving: Signer<'info>,                        // Bob
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8, new_state_bump: u8, new_wallet_bump: u8, new_receipt_bump: u8)]
+pub struct ReassignReceiver<'info> {
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = state_bump,
+        has_one = user_sending,
+        has_one = user_receiving,
+        has_one = mint_of_token_being_sent,
+        close = user_sending,
+    )]
+    application_state: Account<'info, State>,
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = wallet_bump,
+    )]
+    escrow_wallet_state: Account<'info, TokenAccount>,
+    // Bob must still hold the receipt, otherwise the claim belongs to whoever he traded it to
+    #[account(constraint=receipt_wallet.mint == application_state.receipt_mint)]
+    receipt_wallet: Account<'info, TokenAccount>,
+
+    // The PDA pair of the new receiver
+    #[account(
+        init,
+        payer = user_sending,
+        space = State::LEN,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), new_user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = new_state_bump,
+    )]
+    new_application_state: Account<'info, State>,
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), new_user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = new_wallet_bump,
+        token::mint=mint_of_token_being_sent,
+        token::authority=new_application_state,
+    )]
+    new_escrow_wallet_state: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"receipt".as_ref(), user_sending.key().as_ref(), new_user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump = new_receipt_bump,
+        mint::decimals = 0,
+        mint::authority = new_application_state,
+    )]
+    new_receipt_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = user_sending,
+        associated_token::mint = new_receipt_mint,
+        associated_token::authority = new_user_receiving,
+    )]
+    new_receipt_wallet: Account<'info, TokenAccount>,
+
+    // The protocol settings, a grant can't be reassigned while SafePay is paused
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+    )]
+    config: Account<'info, Config>,
+
+    // The denylist PDA of Carol, which must be empty
+    new_receiver_denylist_entry: AccountInfo<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>,                          // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob, signs when he must consent
+    new_user_receiving: AccountInfo<'info>,               // Carol
     mint_of_token_being_sent: Account<'info, Mint>,       // USDC
 
     // Application level accounts
@@ -390,39 +3047,199 @@ pub struct CompleteGrant<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(application_idx: u64, state_bump: u8, wallet_bump: u8)]
-pub struct PullBackInstruction<'info> {
+#[instruction(application_idx: u64, state_bump: u8)]
+pub struct TransferClaim<'info> {
     #[account(
-        mut,
         seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
         bump = state_bump,
         has_one = user_sending,
         has_one = user_receiving,
         has_one = mint_of_token_being_sent,
+        has_one = receipt_mint,
     )]
     application_state: Account<'info, State>,
+
+    // The receipt moves along with the claim
+    receipt_mint: Account<'info, Mint>,
     #[account(
         mut,
-        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
-        bump = wallet_bump,
+        constraint=receipt_wallet.owner == beneficiary.key(),
+        constraint=receipt_wallet.mint == receipt_mint.key(),
+        constraint=receipt_wallet.amount == 1
     )]
-    escrow_wallet_state: Account<'info, TokenAccount>,    
+    receipt_wallet: Account<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = beneficiary,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = new_beneficiary,
+    )]
+    new_receipt_wallet: Account<'info, TokenAccount>,
+
     // Users and accounts in the system
+    user_sending: AccountInfo<'info>,                     // Alice
+    user_receiving: AccountInfo<'info>,                   // Bob
+    mint_of_token_being_sent: Account<'info, Mint>,       // USDC
     #[account(mut)]
-    user_sending: Signer<'info>,
-    user_receiving: AccountInfo<'info>,
-    mint_of_token_being_sent: Account<'info, Mint>,
+    beneficiary: Signer<'info>,                           // The current holder of the receipt
+    new_beneficiary: AccountInfo<'info>,                  // Whoever the claim is assigned to
 
     // Application level accounts
     system_program: Program<'info, System>,
     token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
     rent: Sysvar<'info, Rent>,
+}
 
-    // Wallet to deposit to
+#[derive(Accounts)]
+#[instruction(config_bump: u8)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = Config::LEN,
+        seeds=[b"config".as_ref()],
+        bump = config_bump,
+    )]
+    config: Account<'info, Config>,
+
+    // The upgrade authority of the program, as recorded in its `ProgramData` account
+    #[account(mut)]
+    admin: Signer<'info>,
+    program_data: AccountInfo<'info>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
     #[account(
         mut,
-        constraint=refund_wallet.owner == user_sending.key(),
-        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
     )]
-    refund_wallet: Account<'info, TokenAccount>,
-}
\ No newline at end of file
+    config: Account<'info, Config>,
+
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(allowed_mint_bump: u8)]
+pub struct AllowMint<'info> {
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = AllowedMint::LEN,
+        seeds=[b"allowed_mint".as_ref(), mint.key().as_ref()],
+        bump = allowed_mint_bump,
+    )]
+    allowed_mint: Account<'info, AllowedMint>,
+
+    mint: Account<'info, Mint>,
+    #[account(mut)]
+    admin: Signer<'info>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct SetMintLimits<'info> {
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds=[b"allowed_mint".as_ref(), mint.key().as_ref()],
+        bump = allowed_mint.bump,
+        has_one = mint,
+    )]
+    allowed_mint: Account<'info, AllowedMint>,
+
+    mint: Account<'info, Mint>,
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DisallowMint<'info> {
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds=[b"allowed_mint".as_ref(), mint.key().as_ref()],
+        bump = allowed_mint.bump,
+        has_one = mint,
+        close = admin,
+    )]
+    allowed_mint: Account<'info, AllowedMint>,
+
+    mint: Account<'info, Mint>,
+    #[account(mut)]
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(denied_address_bump: u8)]
+pub struct DenyAddress<'info> {
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    config: Account<'info, Config>,
+    #[account(
+        init,
+        payer = admin,
+        space = DeniedAddress::LEN,
+        seeds=[b"denied".as_ref(), address.key.as_ref()],
+        bump = denied_address_bump,
+    )]
+    denied_address: Account<'info, DeniedAddress>,
+
+    address: AccountInfo<'info>,
+    #[account(mut)]
+    admin: Signer<'info>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UndenyAddress<'info> {
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump = config.bump,
+        has_one = admin,
+    )]
+    config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds=[b"denied".as_ref(), address.key.as_ref()],
+        bump = denied_address.bump,
+        has_one = address,
+        close = admin,
+    )]
+    denied_address: Account<'info, DeniedAddress>,
+
+    address: AccountInfo<'info>,
+    #[account(mut)]
+    admin: Signer<'info>,
+}
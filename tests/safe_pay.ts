@@ -1,4 +1,5 @@
 import assert from "assert";
+import * as crypto from "crypto";
 import * as anchor from '@project-serum/anchor';
 import { Program } from '@project-serum/anchor';
 import * as spl from '@solana/spl-token';
@@ -9,6 +10,14 @@ interface PDAParameters {
     stateKey: anchor.web3.PublicKey,
     escrowBump: number,
     stateBump: number,
+    receiptMintKey: anchor.web3.PublicKey,
+    receiptWalletKey: anchor.web3.PublicKey,
+    receiptBump: number,
+    feeVaultKey: anchor.web3.PublicKey,
+    allowedMintKey: anchor.web3.PublicKey,
+    allowedMintBump: number,
+    senderDenylistKey: anchor.web3.PublicKey,
+    receiverDenylistKey: anchor.web3.PublicKey,
     idx: anchor.BN,
 }
 
@@ -27,6 +36,10 @@ describe('safe_pay', () => {
 
     let pda: PDAParameters;
 
+    // The protocol config is a singleton shared by every test. Fees are off unless a test turns them on.
+    const feeRecipient = new anchor.web3.Keypair();
+    let configKey: anchor.web3.PublicKey;
+
     const getPdaParams = async (connection: anchor.web3.Connection, alice: anchor.web3.PublicKey, bob: anchor.web3.PublicKey, mint: anchor.web3.PublicKey): Promise<PDAParameters> => {
         const uid = new anchor.BN(parseInt((Date.now() / 1000).toString()));
         const uidBuffer = uid.toBuffer('le', 8);
@@ -37,15 +50,54 @@ describe('safe_pay', () => {
         let [walletPubKey, walletBump] = await anchor.web3.PublicKey.findProgramAddress(
             [Buffer.from("wallet"), alice.toBuffer(), bob.toBuffer(), mint.toBuffer(), uidBuffer], program.programId,
         );
+        let [receiptMintPubKey, receiptBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("receipt"), alice.toBuffer(), bob.toBuffer(), mint.toBuffer(), uidBuffer], program.programId,
+        );
+        const receiptWalletPubKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            receiptMintPubKey,
+            bob
+        );
+        const feeVaultPubKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mint,
+            feeRecipient.publicKey
+        );
+        let [allowedMintPubKey, allowedMintBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("allowed_mint"), mint.toBuffer()], program.programId,
+        );
+        let [senderDenylistPubKey, ] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), alice.toBuffer()], program.programId,
+        );
+        let [receiverDenylistPubKey, ] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), bob.toBuffer()], program.programId,
+        );
         return {
             idx: uid,
             escrowBump: walletBump,
             escrowWalletKey: walletPubKey,
             stateBump,
             stateKey: statePubKey,
+            receiptBump,
+            receiptMintKey: receiptMintPubKey,
+            receiptWalletKey: receiptWalletPubKey,
+            feeVaultKey: feeVaultPubKey,
+            allowedMintKey: allowedMintPubKey,
+            allowedMintBump,
+            senderDenylistKey: senderDenylistPubKey,
+            receiverDenylistKey: receiverDenylistPubKey,
         }
     }
 
+    const getDenylistKey = async (address: anchor.web3.PublicKey): Promise<anchor.web3.PublicKey> => {
+        const [denylistKey, ] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), address.toBuffer()], program.programId,
+        );
+        return denylistKey;
+    }
+
     const createMint = async (connection: anchor.web3.Connection): Promise<anchor.web3.PublicKey> => {
         const tokenMint = new anchor.web3.Keypair();
         const lamportsForMint = await provider.connection.getMinimumBalanceForRentExemption(spl.MintLayout.span);
@@ -143,6 +195,60 @@ describe('safe_pay', () => {
         }
     }
 
+    // Closing a grant hands the rent of its state account back to Alice.
+    const closeGrant = (pda: PDAParameters, receiver: anchor.web3.PublicKey) => program.rpc.closeGrant(pda.idx, pda.stateBump, {
+        accounts: {
+            applicationState: pda.stateKey,
+            mintOfTokenBeingSent: mintAddress,
+            userSending: alice.publicKey,
+            userReceiving: receiver,
+        },
+        signers: [alice],
+    });
+
+    const assertCannotCloseGrant = async (pda: PDAParameters, receiver: anchor.web3.PublicKey) => {
+        try {
+            await closeGrant(pda, receiver);
+            assert.fail("Close should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "Stage is invalid");
+        }
+    }
+
+    before(async () => {
+        let configBump;
+        [configKey, configBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("config")], program.programId,
+        );
+        // The program is deployed by the provider wallet, which is therefore its upgrade authority
+        const [programDataKey, ] = await anchor.web3.PublicKey.findProgramAddress(
+            [program.programId.toBuffer()], new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111"),
+        );
+        const initializeConfig = (admin: anchor.web3.PublicKey, signers: anchor.web3.Keypair[]) => program.rpc.initializeConfig(configBump, 0, feeRecipient.publicKey, 100, {
+            accounts: {
+                config: configKey,
+                admin,
+                programData: programDataKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+            signers,
+        });
+
+        // Only the upgrade authority can create the config
+        const [mallory, ] = await createUserAndAssociatedWallet(provider.connection);
+        try {
+            await initializeConfig(mallory.publicKey, [mallory]);
+            assert.fail("Config should only be created by the upgrade authority");
+        } catch (e) {
+            assert.equal(e.msg, "Signer is not the upgrade authority of the program");
+        }
+
+        // The tests administer the protocol with the provider wallet
+        await initializeConfig(provider.wallet.publicKey, []);
+    });
+
     beforeEach(async () => {
         mintAddress = await createMint(provider.connection);
         [alice, aliceWallet] = await createUserAndAssociatedWallet(provider.connection, mintAddress);
@@ -151,6 +257,19 @@ describe('safe_pay', () => {
         [bob, ..._rest] = await createUserAndAssociatedWallet(provider.connection);
 
         pda = await getPdaParams(provider.connection, alice.publicKey, bob.publicKey, mintAddress);
+
+        // Every test mint is approved for grants of any size
+        await program.rpc.allowMint(pda.allowedMintBump, new anchor.BN(1), new anchor.BN("18446744073709551615"), {
+            accounts: {
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                mint: mintAddress,
+                admin: provider.wallet.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+        });
     });
 
     it('can initialize a safe payment by Alice', async () => {
@@ -160,10 +279,16 @@ describe('safe_pay', () => {
         const amount = new anchor.BN(20000000);
 
         // Initialize mint account and fund the account
-        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, amount, {
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
             accounts: {
                 applicationState: pda.stateKey,
                 escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
                 mintOfTokenBeingSent: mintAddress,
                 userSending: alice.publicKey,
                 userReceiving: bob.publicKey,
@@ -172,6 +297,7 @@ describe('safe_pay', () => {
                 systemProgram: anchor.web3.SystemProgram.programId,
                 rent: anchor.web3.SYSVAR_RENT_PUBKEY,
                 tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
             },
             signers: [alice],
         });
@@ -195,10 +321,16 @@ describe('safe_pay', () => {
         const amount = new anchor.BN(20000000);
 
         // Initialize mint account and fund the account
-        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, amount, {
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
             accounts: {
                 applicationState: pda.stateKey,
                 escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
                 mintOfTokenBeingSent: mintAddress,
                 userSending: alice.publicKey,
                 userReceiving: bob.publicKey,
@@ -207,6 +339,7 @@ describe('safe_pay', () => {
                 systemProgram: anchor.web3.SystemProgram.programId,
                 rent: anchor.web3.SYSVAR_RENT_PUBKEY,
                 tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
             },
             signers: [alice],
         });
@@ -225,13 +358,23 @@ describe('safe_pay', () => {
             mintAddress,
             bob.publicKey
         )
-        const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, {
+        const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
             accounts: {
                 applicationState: pda.stateKey,
                 escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                integratorWallet: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
                 mintOfTokenBeingSent: mintAddress,
                 userSending: alice.publicKey,
                 userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
                 walletToDepositTo: bobTokenAccount,
 
                 systemProgram: anchor.web3.SystemProgram.programId,
@@ -262,10 +405,16 @@ describe('safe_pay', () => {
         const amount = new anchor.BN(20000000);
 
         // Initialize mint account and fund the account
-        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, amount, {
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
             accounts: {
                 applicationState: pda.stateKey,
                 escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
                 mintOfTokenBeingSent: mintAddress,
                 userSending: alice.publicKey,
                 userReceiving: bob.publicKey,
@@ -274,6 +423,7 @@ describe('safe_pay', () => {
                 systemProgram: anchor.web3.SystemProgram.programId,
                 rent: anchor.web3.SYSVAR_RENT_PUBKEY,
                 tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
             },
             signers: [alice],
         });
@@ -286,14 +436,26 @@ describe('safe_pay', () => {
         assert.equal(escrowBalancePost, '20000000');
 
         // Withdraw the funds back
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
         const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
             accounts: {
                 applicationState: pda.stateKey,
                 escrowWalletState: pda.escrowWalletKey,
                 mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
                 userSending: alice.publicKey,
                 userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
                 refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
 
                 systemProgram: anchor.web3.SystemProgram.programId,
                 rent: anchor.web3.SYSVAR_RENT_PUBKEY,
@@ -314,10 +476,3321 @@ describe('safe_pay', () => {
             assert.equal(e.message, "Cannot read properties of null (reading 'data')");
         }
 
+        // Bob is owed nothing, so Alice didn't pay for a wallet of his.
+        assert.equal(await provider.connection.getAccountInfo(bobTokenAccount), null);
+
         const state = await program.account.state.fetch(pda.stateKey);
         assert.equal(state.amountTokens.toString(), '20000000');
         assert.equal(state.stage.toString(), '3');
+    })
+
+    it('can release a milestone grant to Bob one tranche at a time', async () => {
+        const milestones = [
+            { amount: new anchor.BN(5000000), descriptionHash: Array(32).fill(1), released: false },
+            { amount: new anchor.BN(15000000), descriptionHash: Array(32).fill(2), released: false },
+        ];
+
+        const tx1 = await program.rpc.initializeMilestoneGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, milestones, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new milestone grant. Alice will pay bob 20 tokens in two tranches`);
+
+        // The whole grant is locked up front.
+        const [, escrowBalancePost] = await readAccount(pda.escrowWalletKey, provider);
+        assert.equal(escrowBalancePost, '20000000');
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const releaseAccounts = {
+            applicationState: pda.stateKey,
+            escrowWalletState: pda.escrowWalletKey,
+            mintOfTokenBeingSent: mintAddress,
+            config: configKey,
+            feeRecipient: feeRecipient.publicKey,
+            feeVault: pda.feeVaultKey,
+            senderDenylistEntry: pda.senderDenylistKey,
+            receiverDenylistEntry: pda.receiverDenylistKey,
+            beneficiaryDenylistEntry: pda.receiverDenylistKey,
+            userSending: alice.publicKey,
+            userReceiving: bob.publicKey,
+            beneficiary: bob.publicKey,
+            walletToDepositTo: bobTokenAccount,
+            receiptMint: pda.receiptMintKey,
+            receiptWallet: pda.receiptWalletKey,
+
+            systemProgram: anchor.web3.SystemProgram.programId,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            tokenProgram: spl.TOKEN_PROGRAM_ID,
+            associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        };
+
+        // Release the first tranche
+        await program.rpc.releaseMilestone(pda.idx, pda.stateBump, pda.escrowBump, 0, {
+            accounts: releaseAccounts,
+            signers: [alice],
+        });
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '5000000');
+        let state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '4');
+        await assertCannotCloseGrant(pda, bob.publicKey);
+
+        // The second tranche empties the escrow and spends the receipt, so Bob has to co-sign it
+        try {
+            await program.rpc.releaseMilestone(pda.idx, pda.stateBump, pda.escrowBump, 1, {
+                accounts: releaseAccounts,
+                signers: [alice],
+            });
+            return assert.fail("Release should need Bob's signature");
+        } catch (e) {
+            assert.equal(e.msg, "Receipt holder must co-sign to burn the receipt");
+        }
+        await program.rpc.releaseMilestone(pda.idx, pda.stateBump, pda.escrowBump, 1, {
+            accounts: releaseAccounts,
+            signers: [alice, bob],
+        });
+        const [, bobBalanceFinal] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalanceFinal, '20000000');
+        const [, bobReceipt] = await readAccount(pda.receiptWalletKey, provider);
+        assert.equal(bobReceipt, '0');
+        state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '2');
+
+        // Assert that escrow was correctly closed.
+        try {
+            await readAccount(pda.escrowWalletKey, provider);
+            return assert.fail("Account should be closed");
+        } catch (e) {
+            assert.equal(e.message, "Cannot read properties of null (reading 'data')");
+        }
+    })
+
+    it('can stream a fully vested grant to Bob', async () => {
+        const amount = new anchor.BN(20000000);
+        const now = Math.floor(Date.now() / 1000);
+        const start = new anchor.BN(now - 1000);
+        const cliff = new anchor.BN(now - 500);
+        const end = new anchor.BN(now - 1);
+
+        const tx1 = await program.rpc.initializeVestingGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, start, cliff, end, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new vesting grant. Alice will stream bob 20 tokens`);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const tx2 = await program.rpc.withdrawVested(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                integratorWallet: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+
+        // The schedule already ended, so everything vested.
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '20000000');
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.amountWithdrawn.toString(), '20000000');
+        assert.equal(state.stage.toString(), '2');
+    })
+
+    it('splits a half vested grant between Bob and Alice on pull back', async () => {
+        const amount = new anchor.BN(20000000);
+        const now = Math.floor(Date.now() / 1000);
+        const start = new anchor.BN(now - 1000);
+        const end = new anchor.BN(now + 1000);
+
+        const tx1 = await program.rpc.initializeVestingGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, start, start, end, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Bob's wallet has to exist before Alice pulls back.
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const txCreateWallet = new anchor.web3.Transaction();
+        txCreateWallet.add(spl.Token.createAssociatedTokenAccountInstruction(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bobTokenAccount,
+            bob.publicKey,
+            bob.publicKey,
+        ));
+        await provider.send(txCreateWallet, [bob]);
+
+        // A wallet that isn't Bob's is rejected.
+        try {
+            await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    refundWallet: aliceWallet,
+                    walletToDepositTo: aliceWallet,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Pull back should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "Beneficiary wallet is invalid");
+        }
+
+        const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Roughly half the schedule elapsed. The validator clock drifts a little from ours, so
+        // allow some slack, but between them Bob and Alice must get every token back.
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        const [, aliceBalance] = await readAccount(aliceWallet, provider);
+        assert.ok(Number(bobBalance) > 9000000 && Number(bobBalance) < 11000000);
+        assert.equal(Number(aliceBalance), 1337000000 - Number(bobBalance));
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.amountWithdrawn.toString(), bobBalance);
+        assert.equal(state.stage.toString(), '3');
+    })
+
+    it('lets anyone return an expired grant to Alice', async () => {
+        const amount = new anchor.BN(20000000);
+        const expiry = new anchor.BN(Math.floor(Date.now() / 1000) + 2);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, expiry, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance that expires in 2 seconds`);
+
+        // The provider wallet cranks the expiry, neither Alice nor Bob sign.
+        const expireGrant = () => program.rpc.expireGrant(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                refundWallet: aliceWallet,
+
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+        });
+
+        // Nobody can expire the grant early
+        try {
+            await expireGrant();
+            return assert.fail("Expiry should be rejected before the deadline");
+        } catch (e) {
+            assert.equal(e.msg, "Grant has not expired yet");
+        }
+
+        // Wait for the grant to expire
+        await new Promise((resolve) => setTimeout(resolve, 5000));
+
+        // Bob is too late to claim
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: bob.publicKey,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [bob],
+            });
+            return assert.fail("Claim should be rejected after the grant expired");
+        } catch (e) {
+            assert.equal(e.msg, "Grant has expired");
+        }
+
+        const tx2 = await expireGrant();
+
+        // Assert that 20 tokens were sent back.
+        const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalanceRefund, '1337000000');
+
+        // Assert that both the escrow and the state were closed.
+        assert.equal(await provider.connection.getAccountInfo(pda.escrowWalletKey), null);
+        assert.equal(await provider.connection.getAccountInfo(pda.stateKey), null);
+    })
 
+    it('cannot pull back funds during the guaranteed claim window', async () => {
+        const amount = new anchor.BN(20000000);
+        const guaranteedUntil = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, guaranteedUntil, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Bob is guaranteed an hour to claim`);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    refundWallet: aliceWallet,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Pull back should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Grant cannot be pulled back during its guaranteed claim window");
+        }
+
+        // The funds are still in escrow for Bob.
+        const [, escrowBalance] = await readAccount(pda.escrowWalletKey, provider);
+        assert.equal(escrowBalance, '20000000');
+    })
+
+    it('lets the arbiter split a disputed grant', async () => {
+        const amount = new anchor.BN(20000000);
+        const [arbiter, ] = await createUserAndAssociatedWallet(provider.connection);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, arbiter.publicKey, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance with an arbiter`);
+
+        // Bob is unhappy and raises a dispute
+        const tx2 = await program.rpc.raiseDispute(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                disputer: bob.publicKey,
+                receiptWallet: pda.receiptWalletKey,
+            },
+            signers: [bob],
+        });
+        let state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '5');
+        await assertCannotCloseGrant(pda, bob.publicKey);
+
+        // The funds are frozen: neither Bob nor Alice can take them while the dispute is open
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: bob.publicKey,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [bob],
+            });
+            return assert.fail("Claim should be rejected while disputed");
+        } catch (e) {
+            assert.equal(e.msg, "Grant is frozen by a dispute");
+        }
+        try {
+            await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    refundWallet: aliceWallet,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Pull back should be rejected while disputed");
+        } catch (e) {
+            assert.equal(e.msg, "Grant is frozen by a dispute");
+        }
+
+        // The arbiter awards 15 tokens to Bob and returns 5 to Alice. Bob's wallet must already exist,
+        // and he co-signs so his receipt can be burned.
+        const txBobWallet = new anchor.web3.Transaction();
+        txBobWallet.add(spl.Token.createAssociatedTokenAccountInstruction(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bobTokenAccount,
+            bob.publicKey,
+            provider.wallet.publicKey,
+        ));
+        await provider.send(txBobWallet);
+        const tx3 = await program.rpc.resolveDispute(pda.idx, pda.stateBump, pda.escrowBump, new anchor.BN(15000000), {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                walletToDepositTo: bobTokenAccount,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                mintOfTokenBeingSent: mintAddress,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                arbiter: arbiter.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [arbiter, bob],
+        });
+
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '15000000');
+        const [, bobReceipt] = await readAccount(pda.receiptWalletKey, provider);
+        assert.equal(bobReceipt, '0');
+        const [, aliceBalance] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalance, '1322000000');
+
+        state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '6');
+    })
+
+    it('lets the arbiter refund Alice after the receipt was burned mid-dispute', async () => {
+        const amount = new anchor.BN(20000000);
+        const [arbiter, ] = await createUserAndAssociatedWallet(provider.connection);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, arbiter.publicKey, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance with an arbiter`);
+
+        const tx2 = await program.rpc.raiseDispute(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                disputer: bob.publicKey,
+                receiptWallet: pda.receiptWalletKey,
+            },
+            signers: [bob],
+        });
+
+        // Bob burns his receipt outside of SafePay, nobody holds the claim anymore
+        const txBurn = new anchor.web3.Transaction();
+        txBurn.add(spl.Token.createBurnInstruction(
+            spl.TOKEN_PROGRAM_ID,
+            pda.receiptMintKey,
+            pda.receiptWalletKey,
+            bob.publicKey,
+            [],
+            1,
+        ));
+        await provider.send(txBurn, [bob]);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const resolveDispute = (amountToReceiver: anchor.BN) => program.rpc.resolveDispute(pda.idx, pda.stateBump, pda.escrowBump, amountToReceiver, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                walletToDepositTo: bobTokenAccount,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                mintOfTokenBeingSent: mintAddress,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                arbiter: arbiter.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [arbiter],
+        });
+
+        // There is nobody left to award anything to
+        try {
+            await resolveDispute(new anchor.BN(5000000));
+            return assert.fail("Resolution should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Receipt wallet does not hold the receipt");
+        }
+
+        // But Alice's funds aren't locked
+        const tx3 = await resolveDispute(new anchor.BN(0));
+        const [, aliceBalance] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalance, '1337000000');
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '6');
+    })
+
+    it('lets the receipt holder raise a dispute, not whoever sold it', async () => {
+        const amount = new anchor.BN(20000000);
+        const [arbiter, ] = await createUserAndAssociatedWallet(provider.connection);
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, arbiter.publicKey, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance with an arbiter`);
+
+        // Bob sells his claim to Carol
+        const carolReceiptWallet = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            pda.receiptMintKey,
+            carol.publicKey
+        )
+        const tx2 = await program.rpc.transferClaim(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                newReceiptWallet: carolReceiptWallet,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                newBeneficiary: carol.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+
+        const raiseDispute = (disputer: anchor.web3.Keypair, receiptWallet: anchor.web3.PublicKey) => program.rpc.raiseDispute(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                disputer: disputer.publicKey,
+                receiptWallet,
+            },
+            signers: [disputer],
+        });
+
+        // Bob no longer has a say in the grant
+        try {
+            await raiseDispute(bob, pda.receiptWalletKey);
+            return assert.fail("Bob should not be able to dispute a claim he sold");
+        } catch (e) {
+            assert.equal(e.msg, "Only Alice or the receipt holder can raise a dispute");
+        }
+
+        // Carol does
+        const tx3 = await raiseDispute(carol, carolReceiptWallet);
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '5');
+    })
+
+    it('only lets Bob claim once Alice approved the release', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, alice.publicKey, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance that requires Alice's approval`);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const completeAccounts = {
+            applicationState: pda.stateKey,
+            escrowWalletState: pda.escrowWalletKey,
+            receiptMint: pda.receiptMintKey,
+            receiptWallet: pda.receiptWalletKey,
+            config: configKey,
+            feeRecipient: feeRecipient.publicKey,
+            feeVault: pda.feeVaultKey,
+            integratorWallet: pda.feeVaultKey,
+            senderDenylistEntry: pda.senderDenylistKey,
+            receiverDenylistEntry: pda.receiverDenylistKey,
+            beneficiaryDenylistEntry: pda.receiverDenylistKey,
+            mintOfTokenBeingSent: mintAddress,
+            userSending: alice.publicKey,
+            userReceiving: bob.publicKey,
+            beneficiary: bob.publicKey,
+            walletToDepositTo: bobTokenAccount,
+
+            systemProgram: anchor.web3.SystemProgram.programId,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            tokenProgram: spl.TOKEN_PROGRAM_ID,
+            associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        };
+
+        // Bob can't claim yet
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: completeAccounts,
+                signers: [bob],
+            });
+            return assert.fail("Claim should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Release has not been approved");
+        }
+
+        const tx2 = await program.rpc.approveRelease(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                approver: alice.publicKey,
+            },
+            signers: [alice],
+        });
+        let state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '7');
+
+        const tx3 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+            accounts: completeAccounts,
+            signers: [bob],
+        });
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '20000000');
+    })
+
+    it('only lets Bob claim once M of N approvers agreed', async () => {
+        const amount = new anchor.BN(20000000);
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const [dave, ] = await createUserAndAssociatedWallet(provider.connection);
+
+        const [approverSetKey, approvalsBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("approvals"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+
+        // The approver of the grant is its approver set
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, approverSetKey, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        const tx2 = await program.rpc.initializeApproverSet(pda.idx, pda.stateBump, approvalsBump, [alice.publicKey, carol.publicKey, dave.publicKey], 2, {
+            accounts: {
+                applicationState: pda.stateKey,
+                approverSet: approverSetKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance that requires 2 of 3 approvals`);
+
+        const approveAccounts = (approver: anchor.web3.PublicKey) => ({
+            applicationState: pda.stateKey,
+            approverSet: approverSetKey,
+            mintOfTokenBeingSent: mintAddress,
+            userSending: alice.publicKey,
+            userReceiving: bob.publicKey,
+            approver,
+        });
+
+        await program.rpc.approve(pda.idx, pda.stateBump, approvalsBump, {
+            accounts: approveAccounts(carol.publicKey),
+            signers: [carol],
+        });
+        let state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '1');
+
+        await program.rpc.approve(pda.idx, pda.stateBump, approvalsBump, {
+            accounts: approveAccounts(dave.publicKey),
+            signers: [dave],
+        });
+        state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.stage.toString(), '7');
+
+        const approverSet = await program.account.approverSet.fetch(approverSetKey);
+        assert.equal(approverSet.approvals, 0b110);
+    })
+
+    it('can escrow native SOL and send it to Bob', async () => {
+        const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+        const [solStateKey, solStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("sol_state"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+
+        const tx1 = await program.rpc.initializeNewSolGrant(pda.idx, solStateBump, amount, {
+            accounts: {
+                applicationState: solStateKey,
+                config: configKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new SOL Safe Pay instance. Alice will pay bob 1 SOL`);
+
+        const aliceBalancePre = await provider.connection.getBalance(alice.publicKey);
+        const bobBalancePre = await provider.connection.getBalance(bob.publicKey);
+        const stateRent = await provider.connection.getBalance(solStateKey) - amount.toNumber();
+        const tx2 = await program.rpc.completeSolGrant(pda.idx, solStateBump, {
+            accounts: {
+                applicationState: solStateKey,
+                config: configKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+            },
+            signers: [bob],
+        });
+
+        // Bob paid the transaction fee out of his own balance
+        const bobBalancePost = await provider.connection.getBalance(bob.publicKey);
+        assert.ok(bobBalancePost - bobBalancePre > 0.99 * anchor.web3.LAMPORTS_PER_SOL);
+
+        // The state account is closed and its rent went back to Alice.
+        assert.equal(await provider.connection.getAccountInfo(solStateKey), null);
+        const aliceBalancePost = await provider.connection.getBalance(alice.publicKey);
+        assert.equal(aliceBalancePost - aliceBalancePre, stateRent);
+    })
+
+    it('can pull back escrowed SOL', async () => {
+        const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+        const [solStateKey, solStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("sol_state"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+
+        const aliceBalancePre = await provider.connection.getBalance(alice.publicKey);
+        const tx1 = await program.rpc.initializeNewSolGrant(pda.idx, solStateBump, amount, {
+            accounts: {
+                applicationState: solStateKey,
+                config: configKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+            signers: [alice],
+        });
+
+        const tx2 = await program.rpc.pullBackSol(pda.idx, solStateBump, {
+            accounts: {
+                applicationState: solStateKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+            },
+            signers: [alice],
+        });
+
+        // Alice got her SOL and the rent back, minus the fees of both transactions.
+        assert.equal(await provider.connection.getAccountInfo(solStateKey), null);
+        const aliceBalancePost = await provider.connection.getBalance(alice.publicKey);
+        assert.ok(aliceBalancePre - aliceBalancePost < 0.001 * anchor.web3.LAMPORTS_PER_SOL);
+    })
+
+    it('rejects an empty SOL grant', async () => {
+        const [solStateKey, solStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("sol_state"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+
+        try {
+            await program.rpc.initializeNewSolGrant(pda.idx, solStateBump, new anchor.BN(0), {
+                accounts: {
+                    applicationState: solStateKey,
+                    config: configKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                },
+                signers: [alice],
+            });
+            return assert.fail("SOL grant should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Amount must be greater than zero");
+        }
+    })
+
+    it('lets Bob claim a hash-time-locked grant with the preimage', async () => {
+        const amount = new anchor.BN(20000000);
+        const preimage = crypto.randomBytes(32);
+        const hashlock = [...crypto.createHash('sha256').update(preimage).digest()];
+        const timeout = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+        const tx1 = await program.rpc.initializeHtlcGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, hashlock, timeout, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new HTLC Safe Pay instance`);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const completeAccounts = {
+            applicationState: pda.stateKey,
+            escrowWalletState: pda.escrowWalletKey,
+            receiptMint: pda.receiptMintKey,
+            receiptWallet: pda.receiptWalletKey,
+            config: configKey,
+            feeRecipient: feeRecipient.publicKey,
+            feeVault: pda.feeVaultKey,
+            integratorWallet: pda.feeVaultKey,
+            senderDenylistEntry: pda.senderDenylistKey,
+            receiverDenylistEntry: pda.receiverDenylistKey,
+            beneficiaryDenylistEntry: pda.receiverDenylistKey,
+            mintOfTokenBeingSent: mintAddress,
+            userSending: alice.publicKey,
+            userReceiving: bob.publicKey,
+            beneficiary: bob.publicKey,
+            walletToDepositTo: bobTokenAccount,
+
+            systemProgram: anchor.web3.SystemProgram.programId,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            tokenProgram: spl.TOKEN_PROGRAM_ID,
+            associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        };
+
+        // A wrong secret is rejected
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, crypto.randomBytes(32), {
+                accounts: completeAccounts,
+                signers: [bob],
+            });
+            return assert.fail("Claim should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Preimage does not match the hashlock");
+        }
+
+        const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, preimage, {
+            accounts: completeAccounts,
+            signers: [bob],
+        });
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '20000000');
+    })
+
+    it('lets the holder of a claim link redeem it to any wallet', async () => {
+        const amount = new anchor.BN(20000000);
+        const claimKey = new anchor.web3.Keypair();
+        const linkPda = await getPdaParams(provider.connection, alice.publicKey, claimKey.publicKey, mintAddress);
+
+        const tx1 = await program.rpc.initializeClaimLinkGrant(linkPda.idx, linkPda.stateBump, linkPda.escrowBump, linkPda.receiptBump, amount, {
+            accounts: {
+                applicationState: linkPda.stateKey,
+                escrowWalletState: linkPda.escrowWalletKey,
+                receiptMint: linkPda.receiptMintKey,
+                receiptWallet: linkPda.receiptWalletKey,
+                config: configKey,
+                allowedMint: linkPda.allowedMintKey,
+                senderDenylistEntry: linkPda.senderDenylistKey,
+                receiverDenylistEntry: linkPda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: claimKey.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new claim link. Whoever holds the key gets 20 tokens`);
+
+        // The link key is the claim, so no receipt is minted for it
+        const [, linkReceipt] = await readAccount(linkPda.receiptWalletKey, provider);
+        assert.equal(linkReceipt, '0');
+        const linkReceiptMint = await readMint(linkPda.receiptMintKey, provider);
+        assert.equal((linkReceiptMint.supply as any as Buffer).readBigUInt64LE().toString(), '0');
+
+        // Bob received the secret and redeems the link to his own wallet
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const claimLink = (signatureIxs: anchor.web3.TransactionInstruction[]) => program.rpc.claimLink(linkPda.idx, linkPda.stateBump, linkPda.escrowBump, {
+            accounts: {
+                applicationState: linkPda.stateKey,
+                escrowWalletState: linkPda.escrowWalletKey,
+                walletToDepositTo: bobTokenAccount,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: linkPda.feeVaultKey,
+                senderDenylistEntry: linkPda.senderDenylistKey,
+                receiverDenylistEntry: linkPda.receiverDenylistKey,
+                destinationDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: claimKey.publicKey,
+                destinationOwner: bob.publicKey,
+                mintOfTokenBeingSent: mintAddress,
+                payer: bob.publicKey,
+
+                instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            instructions: signatureIxs,
+            signers: [bob],
+        });
+
+        // The link can't be redeemed without a signature from its key over the destination
+        const otherKey = new anchor.web3.Keypair();
+        const invalidSignatures = [
+            [],
+            [anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+                privateKey: claimKey.secretKey,
+                message: otherKey.publicKey.toBuffer(),
+            })],
+            [anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+                privateKey: otherKey.secretKey,
+                message: bob.publicKey.toBuffer(),
+            })],
+        ];
+        for (const signatureIxs of invalidSignatures) {
+            try {
+                await claimLink(signatureIxs);
+                return assert.fail("Claim should be rejected");
+            } catch (e) {
+                assert.equal(e.msg, "Claim link signature is missing or invalid");
+            }
+        }
+
+        const signatureIx = anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: claimKey.secretKey,
+            message: bob.publicKey.toBuffer(),
+        });
+        const tx2 = await claimLink([signatureIx]);
+
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '20000000');
+
+        const state = await program.account.state.fetch(linkPda.stateKey);
+        assert.equal(state.stage.toString(), '2');
+    })
+
+    it('can swap escrowed tokens for Bob\'s tokens atomically', async () => {
+        const amount = new anchor.BN(20000000);
+        const amountToReceive = new anchor.BN(7000000);
+
+        // Bob holds a different mint that Alice wants in exchange
+        const otherMint = await createMint(provider.connection);
+        const [carol, carolWallet] = await createUserAndAssociatedWallet(provider.connection, otherMint);
+        const swapPda = await getPdaParams(provider.connection, alice.publicKey, carol.publicKey, mintAddress);
+
+        const tx1 = await program.rpc.initializeSwapGrant(swapPda.idx, swapPda.stateBump, swapPda.escrowBump, swapPda.receiptBump, amount, otherMint, amountToReceive, {
+            accounts: {
+                applicationState: swapPda.stateKey,
+                escrowWalletState: swapPda.escrowWalletKey,
+                receiptMint: swapPda.receiptMintKey,
+                receiptWallet: swapPda.receiptWalletKey,
+                config: configKey,
+                allowedMint: swapPda.allowedMintKey,
+                senderDenylistEntry: swapPda.senderDenylistKey,
+                receiverDenylistEntry: swapPda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: carol.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new swap. Alice offers 20 tokens for 7 of carol's tokens`);
+
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        const aliceOtherTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            otherMint,
+            alice.publicKey
+        )
+        // Alice can't shrink the swap under carol's feet.
+        try {
+            await program.rpc.pullBackPartial(swapPda.idx, swapPda.stateBump, swapPda.escrowBump, new anchor.BN(19000000), {
+                accounts: {
+                    applicationState: swapPda.stateKey,
+                    escrowWalletState: swapPda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    senderDenylistEntry: swapPda.senderDenylistKey,
+                    receiverDenylistEntry: swapPda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: swapPda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: carol.publicKey,
+                    receiptMint: swapPda.receiptMintKey,
+                    receiptWallet: swapPda.receiptWalletKey,
+                    refundWallet: aliceWallet,
+                    walletToDepositTo: carolTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Pull back should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "Swap grants are completed through complete_swap");
+        }
+
+        const tx2 = await program.rpc.completeSwap(swapPda.idx, swapPda.stateBump, swapPda.escrowBump, {
+            accounts: {
+                applicationState: swapPda.stateKey,
+                escrowWalletState: swapPda.escrowWalletKey,
+                walletToDepositTo: carolTokenAccount,
+                walletToPayFrom: carolWallet,
+                walletToReceiveIn: aliceOtherTokenAccount,
+                receiptMint: swapPda.receiptMintKey,
+                receiptWallet: swapPda.receiptWalletKey,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: swapPda.feeVaultKey,
+                senderDenylistEntry: swapPda.senderDenylistKey,
+                receiverDenylistEntry: swapPda.receiverDenylistKey,
+                beneficiaryDenylistEntry: swapPda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: carol.publicKey,
+                beneficiary: carol.publicKey,
+                mintOfTokenBeingSent: mintAddress,
+                mintToReceive: otherMint,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [carol],
+        });
+
+        const [, carolBalance] = await readAccount(carolTokenAccount, provider);
+        assert.equal(carolBalance, '20000000');
+        const [, aliceOtherBalance] = await readAccount(aliceOtherTokenAccount, provider);
+        assert.equal(aliceOtherBalance, '7000000');
+        const [, carolOtherBalance] = await readAccount(carolWallet, provider);
+        assert.equal(carolOtherBalance, '1330000000');
+
+        // The claim is spent
+        const [, carolReceipt] = await readAccount(swapPda.receiptWalletKey, provider);
+        assert.equal(carolReceipt, '0');
+    })
+
+    it('lets whoever holds the receipt of a swap complete it', async () => {
+        const amount = new anchor.BN(20000000);
+        const amountToReceive = new anchor.BN(7000000);
+
+        const otherMint = await createMint(provider.connection);
+        const [carol, carolWallet] = await createUserAndAssociatedWallet(provider.connection, otherMint);
+        const [dave, daveWallet] = await createUserAndAssociatedWallet(provider.connection, otherMint);
+        const swapPda = await getPdaParams(provider.connection, alice.publicKey, carol.publicKey, mintAddress);
+
+        const tx1 = await program.rpc.initializeSwapGrant(swapPda.idx, swapPda.stateBump, swapPda.escrowBump, swapPda.receiptBump, amount, otherMint, amountToReceive, {
+            accounts: {
+                applicationState: swapPda.stateKey,
+                escrowWalletState: swapPda.escrowWalletKey,
+                receiptMint: swapPda.receiptMintKey,
+                receiptWallet: swapPda.receiptWalletKey,
+                config: configKey,
+                allowedMint: swapPda.allowedMintKey,
+                senderDenylistEntry: swapPda.senderDenylistKey,
+                receiverDenylistEntry: swapPda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: carol.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Carol hands her side of the swap to Dave
+        const daveReceiptWallet = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            swapPda.receiptMintKey,
+            dave.publicKey
+        )
+        const tx2 = await program.rpc.transferClaim(swapPda.idx, swapPda.stateBump, {
+            accounts: {
+                applicationState: swapPda.stateKey,
+                receiptMint: swapPda.receiptMintKey,
+                receiptWallet: swapPda.receiptWalletKey,
+                newReceiptWallet: daveReceiptWallet,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: carol.publicKey,
+                beneficiary: carol.publicKey,
+                newBeneficiary: dave.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [carol],
+        });
+
+        const aliceOtherTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            otherMint,
+            alice.publicKey
+        )
+        const completeSwap = async (holder: anchor.web3.Keypair, holderWallet: anchor.web3.PublicKey, holderReceiptWallet: anchor.web3.PublicKey, holderTokenAccount: anchor.web3.PublicKey) =>
+            program.rpc.completeSwap(swapPda.idx, swapPda.stateBump, swapPda.escrowBump, {
+                accounts: {
+                    applicationState: swapPda.stateKey,
+                    escrowWalletState: swapPda.escrowWalletKey,
+                    walletToDepositTo: holderTokenAccount,
+                    walletToPayFrom: holderWallet,
+                    walletToReceiveIn: aliceOtherTokenAccount,
+                    receiptMint: swapPda.receiptMintKey,
+                    receiptWallet: holderReceiptWallet,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: swapPda.feeVaultKey,
+                    senderDenylistEntry: swapPda.senderDenylistKey,
+                    receiverDenylistEntry: swapPda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: await getDenylistKey(holder.publicKey),
+                    userSending: alice.publicKey,
+                    userReceiving: carol.publicKey,
+                    beneficiary: holder.publicKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    mintToReceive: otherMint,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [holder],
+            });
+
+        // Carol gave her receipt away, so she can no longer complete the swap
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        try {
+            await completeSwap(carol, carolWallet, swapPda.receiptWalletKey, carolTokenAccount);
+            return assert.fail("Swap should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "A raw constraint was violated");
+        }
+
+        const daveTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            dave.publicKey
+        )
+        const tx3 = await completeSwap(dave, daveWallet, daveReceiptWallet, daveTokenAccount);
+
+        // Dave paid Alice and got the escrow, Carol is untouched
+        const [, daveBalance] = await readAccount(daveTokenAccount, provider);
+        assert.equal(daveBalance, '20000000');
+        const [, daveOtherBalance] = await readAccount(daveWallet, provider);
+        assert.equal(daveOtherBalance, '1330000000');
+        const [, carolOtherBalance] = await readAccount(carolWallet, provider);
+        assert.equal(carolOtherBalance, '1337000000');
+        const [, aliceOtherBalance] = await readAccount(aliceOtherTokenAccount, provider);
+        assert.equal(aliceOtherBalance, '7000000');
+        const [, daveReceipt] = await readAccount(daveReceiptWallet, provider);
+        assert.equal(daveReceipt, '0');
+    })
+
+    it('can top up an open grant', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        const tx2 = await program.rpc.increaseGrant(pda.idx, pda.stateBump, pda.escrowBump, new anchor.BN(5000000), {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                config: configKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+                allowedMint: pda.allowedMintKey,
+
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const [, aliceBalancePost] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalancePost, '1312000000');
+        const [, escrowBalancePost] = await readAccount(pda.escrowWalletKey, provider);
+        assert.equal(escrowBalancePost, '25000000');
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.amountTokens.toString(), '25000000');
+        assert.equal(state.stage.toString(), '1');
+    })
+
+    it('can pull back part of a grant and leave the rest for Bob', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const tx2 = await program.rpc.pullBackPartial(pda.idx, pda.stateBump, pda.escrowBump, new anchor.BN(8000000), {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalanceRefund, '1325000000');
+        const [, escrowBalance] = await readAccount(pda.escrowWalletKey, provider);
+        assert.equal(escrowBalance, '12000000');
+
+        let state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.amountTokens.toString(), '12000000');
+        assert.equal(state.stage.toString(), '1');
+
+        // Tokens sent straight to the escrow don't count towards the grant.
+        const txStray = new anchor.web3.Transaction();
+        txStray.add(spl.Token.createTransferInstruction(
+            spl.TOKEN_PROGRAM_ID,
+            aliceWallet,
+            pda.escrowWalletKey,
+            alice.publicKey,
+            [],
+            1000000,
+        ));
+        await provider.send(txStray, [alice]);
+
+        const pullBackPartial = (amount: anchor.BN) => program.rpc.pullBackPartial(pda.idx, pda.stateBump, pda.escrowBump, amount, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        try {
+            await pullBackPartial(new anchor.BN(13000000));
+            return assert.fail("Pull back should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "Pull back amount is invalid");
+        }
+
+        // Pulling back the rest of the grant sweeps the escrow and closes it.
+        const tx3 = await pullBackPartial(new anchor.BN(12000000));
+        const [, aliceBalanceSwept] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalanceSwept, '1337000000');
+        assert.equal(await provider.connection.getAccountInfo(pda.escrowWalletKey), null);
+
+        state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.amountTokens.toString(), '0');
+        assert.equal(state.stage.toString(), '3');
+    })
+
+    it('lets Bob reject a grant and return the funds to Alice', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        const tx2 = await program.rpc.rejectGrant(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+
+        // Assert that 20 tokens were sent back.
+        const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalanceRefund, '1337000000');
+
+        // Assert that both the escrow and the state were closed.
+        assert.equal(await provider.connection.getAccountInfo(pda.escrowWalletKey), null);
+        assert.equal(await provider.connection.getAccountInfo(pda.stateKey), null);
+    })
+
+    it('lets Alice close a grant once it is settled', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        // The grant is still open, so its state can't be closed.
+        await assertCannotCloseGrant(pda, bob.publicKey);
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // The grant is settled, so Alice can reclaim the rent of the state account.
+        await closeGrant(pda, bob.publicKey);
+        assert.equal(await provider.connection.getAccountInfo(pda.stateKey), null);
+    })
+
+    it('can reassign an open grant to a new receiver', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        // Alice sent it to the wrong person, Carol should get it instead
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        let [newStateKey, newStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("state"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newWalletKey, newWalletBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("wallet"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newReceiptMintKey, newReceiptBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("receipt"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        const newReceiptWalletKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            newReceiptMintKey,
+            carol.publicKey
+        );
+
+        const tx2 = await program.rpc.reassignReceiver(pda.idx, pda.stateBump, pda.escrowBump, newStateBump, newWalletBump, newReceiptBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptWallet: pda.receiptWalletKey,
+                newApplicationState: newStateKey,
+                newEscrowWalletState: newWalletKey,
+                newReceiptMint: newReceiptMintKey,
+                newReceiptWallet: newReceiptWalletKey,
+                config: configKey,
+                newReceiverDenylistEntry: await getDenylistKey(carol.publicKey),
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                newUserReceiving: carol.publicKey,
+                mintOfTokenBeingSent: mintAddress,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const [, newEscrowBalance] = await readAccount(newWalletKey, provider);
+        assert.equal(newEscrowBalance, '20000000');
+        assert.equal(await provider.connection.getAccountInfo(pda.escrowWalletKey), null);
+        assert.equal(await provider.connection.getAccountInfo(pda.stateKey), null);
+
+        const state = await program.account.state.fetch(newStateKey);
+        assert.equal(state.userReceiving.toBase58(), carol.publicKey.toBase58());
+        assert.equal(state.receiptMint.toBase58(), newReceiptMintKey.toBase58());
+        assert.equal(state.amountTokens.toString(), '20000000');
+        const [, carolReceipt] = await readAccount(newReceiptWalletKey, provider);
+        assert.equal(carolReceipt, '1');
+        assert.equal(state.stage.toString(), '1');
+    })
+
+    it('needs the consent of a receiver who still holds his claim', async () => {
+        const amount = new anchor.BN(20000000);
+        const guaranteedUntil = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, guaranteedUntil, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Bob is guaranteed an hour to claim`);
+
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        let [newStateKey, newStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("state"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newWalletKey, newWalletBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("wallet"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newReceiptMintKey, newReceiptBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("receipt"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        const newReceiptWalletKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            newReceiptMintKey,
+            carol.publicKey
+        );
+        const reassignReceiver = async (signers: anchor.web3.Keypair[]) => program.rpc.reassignReceiver(pda.idx, pda.stateBump, pda.escrowBump, newStateBump, newWalletBump, newReceiptBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptWallet: pda.receiptWalletKey,
+                newApplicationState: newStateKey,
+                newEscrowWalletState: newWalletKey,
+                newReceiptMint: newReceiptMintKey,
+                newReceiptWallet: newReceiptWalletKey,
+                config: configKey,
+                newReceiverDenylistEntry: await getDenylistKey(carol.publicKey),
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                newUserReceiving: carol.publicKey,
+                mintOfTokenBeingSent: mintAddress,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers,
+        });
+
+        // Bob was promised a claim window, Alice can't take it away on her own
+        try {
+            await reassignReceiver([alice]);
+            return assert.fail("Reassignment should need Bob's signature");
+        } catch (e) {
+            assert.equal(e.msg, "Current receiver must co-sign the reassignment");
+        }
+
+        // Once Bob sold his receipt to Dave, the claim is no longer Bob's to give away
+        const [dave, ] = await createUserAndAssociatedWallet(provider.connection);
+        const daveReceiptWallet = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            pda.receiptMintKey,
+            dave.publicKey
+        )
+        const tx2 = await program.rpc.transferClaim(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                newReceiptWallet: daveReceiptWallet,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                newBeneficiary: dave.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+        try {
+            await reassignReceiver([alice, bob]);
+            return assert.fail("Reassignment should be rejected once the claim was sold");
+        } catch (e) {
+            assert.equal(e.msg, "Claim was transferred to another beneficiary");
+        }
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.userReceiving.toBase58(), bob.publicKey.toBase58());
+    })
+
+    it('cannot reassign a grant guarded by an approver set', async () => {
+        const amount = new anchor.BN(20000000);
+        const [approverSetKey, ] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("approvals"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, approverSetKey, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        let [newStateKey, newStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("state"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newWalletKey, newWalletBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("wallet"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newReceiptMintKey, newReceiptBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("receipt"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        const newReceiptWalletKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            newReceiptMintKey,
+            carol.publicKey
+        );
+        try {
+            await program.rpc.reassignReceiver(pda.idx, pda.stateBump, pda.escrowBump, newStateBump, newWalletBump, newReceiptBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    newApplicationState: newStateKey,
+                    newEscrowWalletState: newWalletKey,
+                    newReceiptMint: newReceiptMintKey,
+                    newReceiptWallet: newReceiptWalletKey,
+                    config: configKey,
+                    newReceiverDenylistEntry: await getDenylistKey(carol.publicKey),
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    newUserReceiving: carol.publicKey,
+                    mintOfTokenBeingSent: mintAddress,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Reassignment should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "Grants approved by an approver set cannot be reassigned");
+        }
+
+        const state = await program.account.state.fetch(pda.stateKey);
+        assert.equal(state.userReceiving.toBase58(), bob.publicKey.toBase58());
+    })
+
+    it('pays the beneficiary after Bob transferred his claim', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        console.log(`Initialized a new Safe Pay instance. Alice will pay bob 20 tokens`);
+
+        // Bob factors his invoice to Carol
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const carolReceiptWallet = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            pda.receiptMintKey,
+            carol.publicKey
+        )
+        const tx2 = await program.rpc.transferClaim(pda.idx, pda.stateBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                newReceiptWallet: carolReceiptWallet,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                newBeneficiary: carol.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        const tx3 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: carolReceiptWallet,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                integratorWallet: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: await getDenylistKey(carol.publicKey),
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: carol.publicKey,
+                walletToDepositTo: carolTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [carol],
+        });
+
+        const [, carolBalance] = await readAccount(carolTokenAccount, provider);
+        assert.equal(carolBalance, '20000000');
+    })
+
+    it('pays whoever holds the receipt', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        const [, bobReceipt] = await readAccount(pda.receiptWalletKey, provider);
+        assert.equal(bobReceipt, '1');
+
+        // Bob sells the receipt to Carol outside of SafePay
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const receipt = new spl.Token(provider.connection, pda.receiptMintKey, spl.TOKEN_PROGRAM_ID, bob);
+        const carolReceiptWallet = await receipt.createAssociatedTokenAccount(carol.publicKey);
+        await receipt.transfer(pda.receiptWalletKey, carolReceiptWallet, bob, [], 1);
+
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: carolReceiptWallet,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                integratorWallet: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: await getDenylistKey(carol.publicKey),
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: carol.publicKey,
+                walletToDepositTo: carolTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [carol],
+        });
+
+        const [, carolBalance] = await readAccount(carolTokenAccount, provider);
+        assert.equal(carolBalance, '20000000');
+        const [, carolReceipt] = await readAccount(carolReceiptWallet, provider);
+        assert.equal(carolReceipt, '0');
+    })
+
+    it('releases milestones to whoever holds the receipt', async () => {
+        const milestones = [
+            { amount: new anchor.BN(5000000), descriptionHash: Array(32).fill(1), released: false },
+            { amount: new anchor.BN(15000000), descriptionHash: Array(32).fill(2), released: false },
+        ];
+
+        const tx1 = await program.rpc.initializeMilestoneGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, milestones, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Bob sells the receipt to Carol outside of SafePay
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const receipt = new spl.Token(provider.connection, pda.receiptMintKey, spl.TOKEN_PROGRAM_ID, bob);
+        const carolReceiptWallet = await receipt.createAssociatedTokenAccount(carol.publicKey);
+        await receipt.transfer(pda.receiptWalletKey, carolReceiptWallet, bob, [], 1);
+
+        const releaseTo = async (holder: anchor.web3.PublicKey, holderReceiptWallet: anchor.web3.PublicKey, holderTokenAccount: anchor.web3.PublicKey) =>
+            program.rpc.releaseMilestone(pda.idx, pda.stateBump, pda.escrowBump, 0, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: await getDenylistKey(holder),
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: holder,
+                    walletToDepositTo: holderTokenAccount,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: holderReceiptWallet,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+
+        // Bob no longer holds the receipt, so the tranche can't go to him
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            await releaseTo(bob.publicKey, pda.receiptWalletKey, bobTokenAccount);
+            return assert.fail("Release should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "A raw constraint was violated");
+        }
+
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        await releaseTo(carol.publicKey, carolReceiptWallet, carolTokenAccount);
+        const [, carolBalance] = await readAccount(carolTokenAccount, provider);
+        assert.equal(carolBalance, '5000000');
+    })
+
+    it('lets only the receipt holder reject a grant', async () => {
+        const amount = new anchor.BN(20000000);
+
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Bob sells the receipt to Carol outside of SafePay
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const receipt = new spl.Token(provider.connection, pda.receiptMintKey, spl.TOKEN_PROGRAM_ID, bob);
+        const carolReceiptWallet = await receipt.createAssociatedTokenAccount(carol.publicKey);
+        await receipt.transfer(pda.receiptWalletKey, carolReceiptWallet, bob, [], 1);
+
+        const rejectAs = (holder: anchor.web3.Keypair, holderReceiptWallet: anchor.web3.PublicKey) =>
+            program.rpc.rejectGrant(pda.idx, pda.stateBump, pda.escrowBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: holder.publicKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: holderReceiptWallet,
+                    refundWallet: aliceWallet,
+
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [holder],
+            });
+
+        try {
+            await rejectAs(bob, pda.receiptWalletKey);
+            return assert.fail("Reject should have failed");
+        } catch (e) {
+            assert.equal(e.msg, "A raw constraint was violated");
+        }
+
+        await rejectAs(carol, carolReceiptWallet);
+        const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalanceRefund, '1337000000');
+        const [, carolReceipt] = await readAccount(carolReceiptWallet, provider);
+        assert.equal(carolReceipt, '0');
+    })
+
+    it('takes the protocol fee when Bob claims', async () => {
+        // 1% fee
+        await program.rpc.updateConfig(100, feeRecipient.publicKey, 100, {
+            accounts: {
+                config: configKey,
+                admin: provider.wallet.publicKey,
+            },
+        });
+
+        const amount = new anchor.BN(20000000);
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: bob.publicKey,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [bob],
+            });
+        } finally {
+            await program.rpc.updateConfig(0, feeRecipient.publicKey, 100, {
+                accounts: {
+                    config: configKey,
+                    admin: provider.wallet.publicKey,
+                },
+            });
+        }
+
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '19800000');
+        const [, feeBalance] = await readAccount(pda.feeVaultKey, provider);
+        assert.equal(feeBalance, '200000');
+    })
+
+    it('takes the protocol fee on every milestone tranche', async () => {
+        const milestones = [
+            { amount: new anchor.BN(5000000), descriptionHash: Array(32).fill(1), released: false },
+            { amount: new anchor.BN(15000000), descriptionHash: Array(32).fill(2), released: false },
+        ];
+        const tx1 = await program.rpc.initializeMilestoneGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, milestones, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const releaseMilestone = (milestoneIdx: number, signers: anchor.web3.Keypair[]) => program.rpc.releaseMilestone(pda.idx, pda.stateBump, pda.escrowBump, milestoneIdx, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                walletToDepositTo: bobTokenAccount,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers,
+        });
+
+        // 1% fee
+        await program.rpc.updateConfig(100, feeRecipient.publicKey, 100, {
+            accounts: {
+                config: configKey,
+                admin: provider.wallet.publicKey,
+            },
+        });
+        try {
+            await releaseMilestone(0, [alice]);
+            const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+            assert.equal(bobBalance, '4950000');
+            const [, feeBalance] = await readAccount(pda.feeVaultKey, provider);
+            assert.equal(feeBalance, '50000');
+
+            await releaseMilestone(1, [alice, bob]);
+        } finally {
+            await program.rpc.updateConfig(0, feeRecipient.publicKey, 100, {
+                accounts: {
+                    config: configKey,
+                    admin: provider.wallet.publicKey,
+                },
+            });
+        }
+
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '19800000');
+        const [, feeBalance] = await readAccount(pda.feeVaultKey, provider);
+        assert.equal(feeBalance, '200000');
+    })
+
+    it('pays the integrator its share when Bob claims', async () => {
+        const amount = new anchor.BN(20000000);
+        const [carol, carolWallet] = await createUserAndAssociatedWallet(provider.connection, mintAddress);
+
+        // Carol's platform takes 0.5% of the payment
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, carol.publicKey, 50, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                feeRecipient: feeRecipient.publicKey,
+                feeVault: pda.feeVaultKey,
+                integratorWallet: carolWallet,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                beneficiary: bob.publicKey,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [bob],
+        });
+
+        const [, carolBalance] = await readAccount(carolWallet, provider);
+        assert.equal(carolBalance, '1337100000');
+        const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+        assert.equal(bobBalance, '19900000');
+    })
+
+    it('caps the protocol fee at what the integrator fee leaves of the grant', async () => {
+        const amount = new anchor.BN(20000000);
+        const [carol, carolWallet] = await createUserAndAssociatedWallet(provider.connection, mintAddress);
+
+        // Carol's platform takes 1% of the payment
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, carol.publicKey, 100, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // The admin then raises the protocol fee to 100% while the grant is open
+        await program.rpc.updateConfig(10000, feeRecipient.publicKey, 0, {
+            accounts: {
+                config: configKey,
+                admin: provider.wallet.publicKey,
+            },
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const [, feeBalanceBefore] = await readAccount(pda.feeVaultKey, provider);
+        try {
+            const tx2 = await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: carolWallet,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: bob.publicKey,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [bob],
+            });
+        } finally {
+            await program.rpc.updateConfig(0, feeRecipient.publicKey, 100, {
+                accounts: {
+                    config: configKey,
+                    admin: provider.wallet.publicKey,
+                },
+            });
+        }
+
+        // Carol still gets her 1%, the protocol takes the rest
+        const [, carolBalance] = await readAccount(carolWallet, provider);
+        assert.equal(carolBalance, '1337200000');
+        const [, feeBalanceAfter] = await readAccount(pda.feeVaultKey, provider);
+        assert.equal(new anchor.BN(feeBalanceAfter).sub(new anchor.BN(feeBalanceBefore)).toString(), '19800000');
+        try {
+            await readAccount(pda.escrowWalletKey, provider);
+            return assert.fail("Account should be closed");
+        } catch (e) {
+            assert.equal(e.message, "Cannot read properties of null (reading 'data')");
+        }
+    })
+
+    it('lets Alice pull back but not Bob claim while paused', async () => {
+        const amount = new anchor.BN(20000000);
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // The admin hands over to Carol, who pauses SafePay
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        await program.rpc.transferAdmin(carol.publicKey, {
+            accounts: {
+                config: configKey,
+                admin: provider.wallet.publicKey,
+            },
+        });
+        await program.rpc.setPaused(true, {
+            accounts: {
+                config: configKey,
+                admin: carol.publicKey,
+            },
+            signers: [carol],
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            try {
+                await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                    accounts: {
+                        applicationState: pda.stateKey,
+                        escrowWalletState: pda.escrowWalletKey,
+                        receiptMint: pda.receiptMintKey,
+                        receiptWallet: pda.receiptWalletKey,
+                        config: configKey,
+                        feeRecipient: feeRecipient.publicKey,
+                        feeVault: pda.feeVaultKey,
+                        integratorWallet: pda.feeVaultKey,
+                        senderDenylistEntry: pda.senderDenylistKey,
+                        receiverDenylistEntry: pda.receiverDenylistKey,
+                        beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                        mintOfTokenBeingSent: mintAddress,
+                        userSending: alice.publicKey,
+                        userReceiving: bob.publicKey,
+                        beneficiary: bob.publicKey,
+                        walletToDepositTo: bobTokenAccount,
+
+                        systemProgram: anchor.web3.SystemProgram.programId,
+                        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                        tokenProgram: spl.TOKEN_PROGRAM_ID,
+                        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                    },
+                    signers: [bob],
+                });
+                return assert.fail("Claim should be rejected");
+            } catch (e) {
+                assert.equal(e.msg, "SafePay is paused");
+            }
+
+            // Nor can Alice put more funds in, in any asset
+            try {
+                await program.rpc.increaseGrant(pda.idx, pda.stateBump, pda.escrowBump, new anchor.BN(5000000), {
+                    accounts: {
+                        applicationState: pda.stateKey,
+                        escrowWalletState: pda.escrowWalletKey,
+                        mintOfTokenBeingSent: mintAddress,
+                        config: configKey,
+                        userSending: alice.publicKey,
+                        userReceiving: bob.publicKey,
+                        walletToWithdrawFrom: aliceWallet,
+                        allowedMint: pda.allowedMintKey,
+
+                        tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    },
+                    signers: [alice],
+                });
+                return assert.fail("Top-up should be rejected");
+            } catch (e) {
+                assert.equal(e.msg, "SafePay is paused");
+            }
+            const [solStateKey, solStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+                [Buffer.from("sol_state"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+            );
+            try {
+                await program.rpc.initializeNewSolGrant(pda.idx, solStateBump, new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), {
+                    accounts: {
+                        applicationState: solStateKey,
+                        config: configKey,
+                        senderDenylistEntry: pda.senderDenylistKey,
+                        receiverDenylistEntry: pda.receiverDenylistKey,
+                        userSending: alice.publicKey,
+                        userReceiving: bob.publicKey,
+
+                        systemProgram: anchor.web3.SystemProgram.programId,
+                        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    },
+                    signers: [alice],
+                });
+                return assert.fail("SOL grant should be rejected");
+            } catch (e) {
+                assert.equal(e.msg, "SafePay is paused");
+            }
+
+            const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    refundWallet: aliceWallet,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            const [, aliceBalance] = await readAccount(aliceWallet, provider);
+            assert.equal(aliceBalance, '1337000000');
+        } finally {
+            await program.rpc.setPaused(false, {
+                accounts: {
+                    config: configKey,
+                    admin: carol.publicKey,
+                },
+                signers: [carol],
+            });
+            await program.rpc.transferAdmin(provider.wallet.publicKey, {
+                accounts: {
+                    config: configKey,
+                    admin: carol.publicKey,
+                },
+                signers: [carol],
+            });
+        }
+    })
+
+    it('rejects grants in a mint that is not on the allowlist', async () => {
+        const amount = new anchor.BN(20000000);
+        const otherMint = await createMint(provider.connection);
+        const [dave, daveWallet] = await createUserAndAssociatedWallet(provider.connection, otherMint);
+        const otherPda = await getPdaParams(provider.connection, dave.publicKey, bob.publicKey, otherMint);
+
+        try {
+            await program.rpc.initializeNewGrant(otherPda.idx, otherPda.stateBump, otherPda.escrowBump, otherPda.receiptBump, amount, null, null, null, null, null, 0, {
+                accounts: {
+                    applicationState: otherPda.stateKey,
+                    escrowWalletState: otherPda.escrowWalletKey,
+                    receiptMint: otherPda.receiptMintKey,
+                    receiptWallet: otherPda.receiptWalletKey,
+                    config: configKey,
+                    allowedMint: otherPda.allowedMintKey,
+                    senderDenylistEntry: otherPda.senderDenylistKey,
+                    receiverDenylistEntry: otherPda.receiverDenylistKey,
+                    mintOfTokenBeingSent: otherMint,
+                    userSending: dave.publicKey,
+                    userReceiving: bob.publicKey,
+                    walletToWithdrawFrom: daveWallet,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [dave],
+            });
+            return assert.fail("Grant should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Mint is not on the allowlist");
+        }
+    })
+
+    it('enforces the amount limits of the mint', async () => {
+        await program.rpc.setMintLimits(new anchor.BN(1000000), new anchor.BN(25000000), {
+            accounts: {
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                mint: mintAddress,
+                admin: provider.wallet.publicKey,
+            },
+        });
+
+        const initialize = (amount: anchor.BN) => program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        try {
+            await initialize(new anchor.BN(0));
+            return assert.fail("Grant should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Amount must be greater than zero");
+        }
+        try {
+            await initialize(new anchor.BN(30000000));
+            return assert.fail("Grant should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Amount is above the maximum for this mint");
+        }
+        await initialize(new anchor.BN(20000000));
+
+        // A top-up can't push the grant over the maximum either
+        try {
+            await program.rpc.increaseGrant(pda.idx, pda.stateBump, pda.escrowBump, new anchor.BN(10000000), {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    config: configKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    walletToWithdrawFrom: aliceWallet,
+                    allowedMint: pda.allowedMintKey,
+
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Top-up should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Amount is above the maximum for this mint");
+        }
+    })
+
+    it('blocks claims by a denied receipt holder', async () => {
+        const amount = new anchor.BN(20000000);
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Bob sells the receipt to Carol, who is sanctioned
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const receipt = new spl.Token(provider.connection, pda.receiptMintKey, spl.TOKEN_PROGRAM_ID, bob);
+        const carolReceiptWallet = await receipt.createAssociatedTokenAccount(carol.publicKey);
+        await receipt.transfer(pda.receiptWalletKey, carolReceiptWallet, bob, [], 1);
+
+        const [carolDenylistKey, deniedBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), carol.publicKey.toBuffer()], program.programId,
+        );
+        await program.rpc.denyAddress(deniedBump, {
+            accounts: {
+                config: configKey,
+                deniedAddress: carolDenylistKey,
+                address: carol.publicKey,
+                admin: provider.wallet.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+        });
+
+        const carolTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            carol.publicKey
+        )
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: carolReceiptWallet,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: carolDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: carol.publicKey,
+                    walletToDepositTo: carolTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [carol],
+            });
+            return assert.fail("Claim should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Address is on the denylist");
+        }
+
+        // Alice can still get her funds back
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: carolDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: carolReceiptWallet,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        const [, aliceBalance] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalance, '1337000000');
+    })
+
+    it('keeps a denied address out of SOL grants and reassignments', async () => {
+        const amount = new anchor.BN(20000000);
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Carol is sanctioned
+        const [carol, ] = await createUserAndAssociatedWallet(provider.connection);
+        const [carolDenylistKey, deniedBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), carol.publicKey.toBuffer()], program.programId,
+        );
+        await program.rpc.denyAddress(deniedBump, {
+            accounts: {
+                config: configKey,
+                deniedAddress: carolDenylistKey,
+                address: carol.publicKey,
+                admin: provider.wallet.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+        });
+
+        // Alice can't send her SOL
+        const [solStateKey, solStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("sol_state"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        try {
+            await program.rpc.initializeNewSolGrant(pda.idx, solStateBump, new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), {
+                accounts: {
+                    applicationState: solStateKey,
+                    config: configKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: carolDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: carol.publicKey,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                },
+                signers: [alice],
+            });
+            return assert.fail("SOL grant should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Address is on the denylist");
+        }
+
+        // Nor move Bob's grant over to her
+        let [newStateKey, newStateBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("state"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newWalletKey, newWalletBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("wallet"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        let [newReceiptMintKey, newReceiptBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("receipt"), alice.publicKey.toBuffer(), carol.publicKey.toBuffer(), mintAddress.toBuffer(), pda.idx.toBuffer('le', 8)], program.programId,
+        );
+        const newReceiptWalletKey = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            newReceiptMintKey,
+            carol.publicKey
+        )
+        try {
+            await program.rpc.reassignReceiver(pda.idx, pda.stateBump, pda.escrowBump, newStateBump, newWalletBump, newReceiptBump, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    newApplicationState: newStateKey,
+                    newEscrowWalletState: newWalletKey,
+                    newReceiptMint: newReceiptMintKey,
+                    newReceiptWallet: newReceiptWalletKey,
+                    config: configKey,
+                    newReceiverDenylistEntry: carolDenylistKey,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    newUserReceiving: carol.publicKey,
+                    mintOfTokenBeingSent: mintAddress,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [alice],
+            });
+            return assert.fail("Reassignment should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Address is on the denylist");
+        }
+    })
+
+    it('blocks claims by a denied receiver but lets Alice pull back', async () => {
+        const amount = new anchor.BN(20000000);
+        const tx1 = await program.rpc.initializeNewGrant(pda.idx, pda.stateBump, pda.escrowBump, pda.receiptBump, amount, null, null, null, null, null, 0, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                config: configKey,
+                allowedMint: pda.allowedMintKey,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                mintOfTokenBeingSent: mintAddress,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                walletToWithdrawFrom: aliceWallet,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+
+        // Bob gets sanctioned after the grant was created
+        const [, deniedBump] = await anchor.web3.PublicKey.findProgramAddress(
+            [Buffer.from("denied"), bob.publicKey.toBuffer()], program.programId,
+        );
+        await program.rpc.denyAddress(deniedBump, {
+            accounts: {
+                config: configKey,
+                deniedAddress: pda.receiverDenylistKey,
+                address: bob.publicKey,
+                admin: provider.wallet.publicKey,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+            },
+        });
+
+        const bobTokenAccount = await spl.Token.getAssociatedTokenAddress(
+            spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+            spl.TOKEN_PROGRAM_ID,
+            mintAddress,
+            bob.publicKey
+        )
+        try {
+            await program.rpc.completeGrant(pda.idx, pda.stateBump, pda.escrowBump, null, {
+                accounts: {
+                    applicationState: pda.stateKey,
+                    escrowWalletState: pda.escrowWalletKey,
+                    receiptMint: pda.receiptMintKey,
+                    receiptWallet: pda.receiptWalletKey,
+                    config: configKey,
+                    feeRecipient: feeRecipient.publicKey,
+                    feeVault: pda.feeVaultKey,
+                    integratorWallet: pda.feeVaultKey,
+                    senderDenylistEntry: pda.senderDenylistKey,
+                    receiverDenylistEntry: pda.receiverDenylistKey,
+                    beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                    mintOfTokenBeingSent: mintAddress,
+                    userSending: alice.publicKey,
+                    userReceiving: bob.publicKey,
+                    beneficiary: bob.publicKey,
+                    walletToDepositTo: bobTokenAccount,
+
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                    rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                    tokenProgram: spl.TOKEN_PROGRAM_ID,
+                    associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+                },
+                signers: [bob],
+            });
+            return assert.fail("Claim should be rejected");
+        } catch (e) {
+            assert.equal(e.msg, "Address is on the denylist");
+        }
+
+        const tx2 = await program.rpc.pullBack(pda.idx, pda.stateBump, pda.escrowBump, {
+            accounts: {
+                applicationState: pda.stateKey,
+                escrowWalletState: pda.escrowWalletKey,
+                mintOfTokenBeingSent: mintAddress,
+                senderDenylistEntry: pda.senderDenylistKey,
+                receiverDenylistEntry: pda.receiverDenylistKey,
+                beneficiaryDenylistEntry: pda.receiverDenylistKey,
+                userSending: alice.publicKey,
+                userReceiving: bob.publicKey,
+                receiptMint: pda.receiptMintKey,
+                receiptWallet: pda.receiptWalletKey,
+                refundWallet: aliceWallet,
+                walletToDepositTo: bobTokenAccount,
+
+                systemProgram: anchor.web3.SystemProgram.programId,
+                rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+                tokenProgram: spl.TOKEN_PROGRAM_ID,
+            },
+            signers: [alice],
+        });
+        const [, aliceBalance] = await readAccount(aliceWallet, provider);
+        assert.equal(aliceBalance, '1337000000');
     })
 
 });
\ No newline at end of file